@@ -0,0 +1,189 @@
+use crate::driver::{expired, timed_out, would_block, Driver, CLOSE_TIMEOUT, TIMED_OUT, WAIT_SLICE};
+use crate::error::Error;
+use ssh2::Channel;
+use std::future::{poll_fn, Future};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::{Mutex, MutexGuard};
+use tokio::time::{self, Instant, Sleep};
+
+/// A session driven from async code.
+///
+/// libssh2 runs in non-blocking mode just as under `Driver`, but a call that would
+/// block parks the task until the runtime reports the socket ready instead of
+/// polling it. Tasks also wake every `WAIT_SLICE`, since another task sharing the
+/// session may read the data they were waiting for.
+#[derive(Clone)]
+pub(crate) struct AsyncDriver {
+    pub(crate) driver: Driver,
+    socket: Arc<AsyncFd<TcpStream>>,
+    /// The async counterpart of the session's `OpenLock`.
+    opening: Arc<Mutex<()>>,
+}
+
+impl AsyncDriver {
+    /// Registers the session socket with the current Tokio runtime.
+    pub(crate) fn new(driver: Driver) -> io::Result<Self> {
+        let socket = AsyncFd::new(driver.clone_socket()?)?;
+        Ok(Self {
+            driver,
+            socket: Arc::new(socket),
+            opening: Arc::default(),
+        })
+    }
+
+    /// Ready once the socket is ready in the direction libssh2 last blocked on, or
+    /// once `slice` fires, which then starts over.
+    pub(crate) fn poll_wait(&self, cx: &mut Context<'_>, slice: &mut Pin<Box<Sleep>>) -> Poll<()> {
+        let (read, write) = self.driver.directions();
+        if read {
+            if let Poll::Ready(res) = self.socket.poll_read_ready(cx) {
+                if let Ok(mut guard) = res {
+                    guard.clear_ready();
+                }
+                return Poll::Ready(());
+            }
+        }
+        if write {
+            if let Poll::Ready(res) = self.socket.poll_write_ready(cx) {
+                if let Ok(mut guard) = res {
+                    guard.clear_ready();
+                }
+                return Poll::Ready(());
+            }
+        }
+        if slice.as_mut().poll(cx).is_ready() {
+            slice.as_mut().reset(Instant::now() + WAIT_SLICE);
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+
+    /// Waits until the socket is ready in the direction libssh2 last blocked on,
+    /// or at most `WAIT_SLICE`.
+    pub(crate) async fn wait(&self) {
+        let mut slice = Box::pin(time::sleep(WAIT_SLICE));
+        poll_fn(|cx| self.poll_wait(cx, &mut slice)).await
+    }
+
+    /// Runs a libssh2 call until it stops reporting that it would block.
+    pub(crate) async fn retry<T, F>(&self, mut op: F) -> Result<T, ssh2::Error>
+    where
+        F: FnMut() -> Result<T, ssh2::Error>,
+    {
+        let limit = self.driver.limit();
+        loop {
+            match op() {
+                Err(ref e) if would_block(e) => {
+                    if expired(limit) {
+                        return Err(timed_out());
+                    }
+                    self.wait().await
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Async `Driver::opening`: waits until no other open is under way on the
+    /// session, within the limit of a call starting now.
+    pub(crate) async fn opening(&self) -> Result<MutexGuard<'_, ()>, Error> {
+        let lock = self.opening.lock();
+        match self.driver.limit() {
+            Some(limit) => time::timeout_at(Instant::from_std(limit), lock).await.map_err(|_| Error::Timeout),
+            None => Ok(lock.await),
+        }
+    }
+
+    /// Async `Driver::open`.
+    pub(crate) async fn open<T, F>(&self, op: F) -> Result<T, ssh2::Error>
+    where
+        F: FnMut() -> Result<T, ssh2::Error>,
+    {
+        let _opening = self.opening().await.map_err(|_| timed_out())?;
+        self.retry(op).await
+    }
+
+    /// Runs an I/O call on a channel until it stops reporting `WouldBlock`.
+    pub(crate) async fn retry_io<T, F>(&self, mut op: F) -> io::Result<T>
+    where
+        F: FnMut() -> io::Result<T>,
+    {
+        let limit = self.driver.limit();
+        loop {
+            match op() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if expired(limit) {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, TIMED_OUT));
+                    }
+                    self.wait().await
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                res => return res,
+            }
+        }
+    }
+
+    /// `retry_io` for `poll_*` methods, without a time limit.
+    pub(crate) fn poll_io<T, F>(&self, cx: &mut Context<'_>, slice: &mut Pin<Box<Sleep>>, mut op: F) -> Poll<io::Result<T>>
+    where
+        F: FnMut() -> io::Result<T>,
+    {
+        loop {
+            match op() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if self.poll_wait(cx, slice).is_pending() {
+                        return Poll::Pending;
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                res => return Poll::Ready(res),
+            }
+        }
+    }
+
+    /// Async `read` on a channel or stream of this session.
+    pub(crate) async fn read<R: Read>(&self, reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+        self.retry_io(|| reader.read(buf)).await
+    }
+
+    /// Async `write_all` on a channel or stream of this session.
+    pub(crate) async fn write_all<W: Write>(&self, writer: &mut W, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            match self.retry_io(|| writer.write(data)).await? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => data = &data[n..],
+            }
+        }
+        self.retry_io(|| writer.flush()).await
+    }
+
+    /// Async `Driver::close_and_wait`.
+    pub(crate) async fn close_and_wait(&self, channel: &mut Channel) -> Result<(), Error> {
+        self.close_until(channel, self.driver.limit()).await
+    }
+
+    /// Async `Driver::close`.
+    pub(crate) async fn close(&self, channel: &mut Channel) -> Result<(), Error> {
+        self.close_until(channel, Some((Instant::now() + CLOSE_TIMEOUT).into_std())).await
+    }
+
+    async fn close_until(&self, channel: &mut Channel, limit: Option<std::time::Instant>) -> Result<(), Error> {
+        loop {
+            match channel.close() {
+                Ok(()) => return Ok(()),
+                Err(ref e) if would_block(e) => {
+                    if expired(limit) {
+                        return Err(Error::Timeout);
+                    }
+                    self.wait().await;
+                }
+                Err(_) => return Err(Error::ConnectionLost),
+            }
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use crate::async_driver::AsyncDriver;
+use crate::driver::WAIT_SLICE;
+use crate::error::Error;
+use ssh2::{Channel, Listener};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{self, Sleep};
+
+/// A connection to a host as seen from the server, for async code: the
+/// counterpart of `Tunnel`. Shutting down the write half sends EOF.
+pub struct AsyncTunnel {
+    channel: Channel,
+    driver: AsyncDriver,
+    slice: Pin<Box<Sleep>>,
+}
+
+impl AsyncTunnel {
+    pub(crate) async fn open(driver: &AsyncDriver, host: &str, port: u16, src: Option<(&str, u16)>) -> Result<Self, Error> {
+        let sess = &driver.driver.sess;
+        let channel = driver
+            .open(|| sess.channel_direct_tcpip(host, port, src))
+            .await
+            .map_err(Error::channel)?;
+        Ok(Self::new(driver, channel))
+    }
+
+    fn new(driver: &AsyncDriver, channel: Channel) -> Self {
+        Self {
+            channel,
+            driver: driver.clone(),
+            slice: Box::pin(time::sleep(WAIT_SLICE)),
+        }
+    }
+}
+
+impl AsyncRead for AsyncTunnel {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let Self { channel, driver, slice } = self.get_mut();
+        driver.poll_io(cx, slice, || {
+            let n = channel.read(buf.initialize_unfilled())?;
+            buf.advance(n);
+            Ok(())
+        })
+    }
+}
+
+impl AsyncWrite for AsyncTunnel {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        let Self { channel, driver, slice } = self.get_mut();
+        driver.poll_io(cx, slice, || channel.write(data))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Self { channel, driver, slice } = self.get_mut();
+        driver.poll_io(cx, slice, || channel.flush())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Self { channel, driver, slice } = self.get_mut();
+        driver.poll_io(cx, slice, || channel.send_eof().map_err(io::Error::from))
+    }
+}
+
+impl Drop for AsyncTunnel {
+    fn drop(&mut self) {
+        // Waiting for the server to acknowledge would block the runtime.
+        let _ = self.channel.close();
+    }
+}
+
+/// Local port forwarding for async code, the counterpart of `LocalForward`.
+///
+/// Runs as a task on the runtime it was started from until `stop` is called or
+/// the handle is dropped, which also closes every open connection.
+pub struct AsyncLocalForward {
+    local_addr: SocketAddr,
+    task: JoinHandle<Result<(), Error>>,
+}
+
+impl AsyncLocalForward {
+    pub(crate) async fn start<A: ToSocketAddrs>(driver: AsyncDriver, bind_addr: A, host: &str, port: u16) -> Result<Self, Error> {
+        let listener = TcpListener::bind(bind_addr).await?;
+        let local_addr = listener.local_addr()?;
+        let host: Arc<str> = host.into();
+        let task = tokio::spawn(async move {
+            let mut connections = JoinSet::new();
+            loop {
+                let (stream, peer) = listener.accept().await?;
+                while connections.try_join_next().is_some() {}
+                let (driver, host) = (driver.clone(), Arc::clone(&host));
+                connections.spawn(async move {
+                    let src = peer.ip().to_string();
+                    if let Ok(tunnel) = AsyncTunnel::open(&driver, &host, port, Some((&src, peer.port()))).await {
+                        pipe(stream, tunnel).await;
+                    }
+                });
+            }
+        });
+        Ok(Self { local_addr, task })
+    }
+
+    /// The address the forward is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// False once the forward has stopped accepting connections.
+    pub fn is_running(&self) -> bool {
+        !self.task.is_finished()
+    }
+
+    /// Stops the forward, closing every open connection, and returns the error
+    /// that stopped it earlier, if any.
+    pub async fn stop(mut self) -> Result<(), Error> {
+        stop(&mut self.task).await
+    }
+}
+
+impl Drop for AsyncLocalForward {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Remote port forwarding for async code, the counterpart of `RemoteForward`.
+///
+/// Runs as a task on the runtime it was started from until `stop` is called or
+/// the handle is dropped, which also closes every open connection.
+pub struct AsyncRemoteForward {
+    remote_port: u16,
+    task: JoinHandle<Result<(), Error>>,
+}
+
+impl AsyncRemoteForward {
+    pub(crate) async fn start<A: ToSocketAddrs>(
+        driver: AsyncDriver,
+        bind_host: Option<&str>,
+        remote_port: u16,
+        target: A,
+    ) -> Result<Self, Error> {
+        let target: Vec<SocketAddr> = tokio::net::lookup_host(target).await?.collect();
+        let sess = &driver.driver.sess;
+        let (listener, remote_port) = driver
+            .open(|| sess.channel_forward_listen(remote_port, bind_host, None))
+            .await
+            .map_err(Error::channel)?;
+        let task = tokio::spawn(accept_remote(driver, listener, target));
+        Ok(Self { remote_port, task })
+    }
+
+    /// The port the server is listening on, useful when 0 was requested.
+    pub fn remote_port(&self) -> u16 {
+        self.remote_port
+    }
+
+    /// False once the forward has stopped accepting connections.
+    pub fn is_running(&self) -> bool {
+        !self.task.is_finished()
+    }
+
+    /// Stops the forward, closing every open connection, and returns the error
+    /// that stopped it earlier, if any.
+    pub async fn stop(mut self) -> Result<(), Error> {
+        stop(&mut self.task).await
+    }
+}
+
+impl Drop for AsyncRemoteForward {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Connects every channel the server opens on `listener` to `target`.
+async fn accept_remote(mut driver: AsyncDriver, mut listener: Listener, target: Vec<SocketAddr>) -> Result<(), Error> {
+    // Waiting for the next connection is not bounded by the operation timeout.
+    driver.driver.set_timeout(None);
+    let mut connections = JoinSet::new();
+    loop {
+        let channel = driver.retry(|| listener.accept()).await.map_err(Error::channel)?;
+        while connections.try_join_next().is_some() {}
+        let (driver, target) = (driver.clone(), target.clone());
+        connections.spawn(async move {
+            let tunnel = AsyncTunnel::new(&driver, channel);
+            if let Ok(stream) = TcpStream::connect(&target[..]).await {
+                pipe(stream, tunnel).await;
+            }
+        });
+    }
+}
+
+/// Copies both ways between a local connection and a channel until both are done.
+async fn pipe(mut stream: TcpStream, mut tunnel: AsyncTunnel) {
+    let _ = tokio::io::copy_bidirectional(&mut stream, &mut tunnel).await;
+}
+
+/// Aborts a forward's task and collects how it ended.
+async fn stop(task: &mut JoinHandle<Result<(), Error>>) -> Result<(), Error> {
+    task.abort();
+    // A task that was still running reports being cancelled.
+    task.await.unwrap_or(Ok(()))
+}
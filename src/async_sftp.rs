@@ -0,0 +1,213 @@
+use crate::async_driver::AsyncDriver;
+use crate::driver::Driver;
+use crate::error::Error;
+use crate::scp::CHUNK_SIZE;
+use crate::sftp::{self, FileStat, OpenFlags, FILEMODE};
+use ssh2::OpenType;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+
+/// An SFTP session for async code: the counterpart of `Sftp`, with whole-file
+/// transfers in place of open remote files.
+///
+/// Shutting the subsystem down waits for the server in blocking mode, so it runs
+/// on a blocking thread of the runtime. Call `close` to wait for it to finish;
+/// dropping the handle starts it in the background.
+pub struct AsyncSftp {
+    /// Only taken when the handle is closed or dropped.
+    sftp: Option<ssh2::Sftp>,
+    driver: AsyncDriver,
+}
+
+impl AsyncSftp {
+    /// Starts the `sftp` subsystem on a new channel.
+    pub(crate) async fn start(driver: &AsyncDriver) -> Result<Self, Error> {
+        let sess = &driver.driver.sess;
+        let sftp = driver.open(|| sess.sftp()).await.map_err(Error::sftp)?;
+        Ok(Self {
+            sftp: Some(sftp),
+            driver: driver.clone(),
+        })
+    }
+
+    fn sftp(&self) -> &ssh2::Sftp {
+        self.sftp.as_ref().expect("sftp is only taken on close")
+    }
+
+    /// Runs an SFTP call to completion.
+    async fn call<T, F>(&self, op: F) -> Result<T, Error>
+    where
+        F: FnMut() -> Result<T, ssh2::Error>,
+    {
+        self.driver.retry(op).await.map_err(Error::sftp)
+    }
+
+    /// Opens a remote file or directory, closing it off the runtime if it is
+    /// dropped on an error path.
+    async fn open<F>(&self, op: F) -> Result<RemoteHandle<'_>, Error>
+    where
+        F: FnMut() -> Result<ssh2::File, ssh2::Error>,
+    {
+        let file = self.call(op).await?;
+        Ok(RemoteHandle {
+            file: Some(file),
+            driver: &self.driver,
+        })
+    }
+
+    /// Lists a directory, without `.` and `..`. Returned paths are joined onto `dir`.
+    pub async fn readdir(&self, dir: &Path) -> Result<Vec<(PathBuf, FileStat)>, Error> {
+        let mut handle = self.open(|| self.sftp().opendir(dir)).await?;
+        let mut entries = Vec::new();
+        while sftp::list_entry(dir, self.driver.retry(|| handle.readdir()).await, &mut entries)? {}
+        handle.close().await?;
+        Ok(entries)
+    }
+
+    /// Metadata for a path, following symlinks.
+    pub async fn stat(&self, path: &Path) -> Result<FileStat, Error> {
+        self.call(|| self.sftp().stat(path)).await
+    }
+
+    /// Creates a directory with the given permission bits.
+    pub async fn mkdir(&self, path: &Path, mode: u32) -> Result<(), Error> {
+        self.call(|| self.sftp().mkdir(path, mode as i32)).await
+    }
+
+    /// Removes a file.
+    pub async fn remove_file(&self, path: &Path) -> Result<(), Error> {
+        self.call(|| self.sftp().unlink(path)).await
+    }
+
+    /// Removes an empty directory.
+    pub async fn remove_dir(&self, path: &Path) -> Result<(), Error> {
+        self.call(|| self.sftp().rmdir(path)).await
+    }
+
+    /// Renames a file or directory.
+    pub async fn rename(&self, src: &Path, dst: &Path) -> Result<(), Error> {
+        self.call(|| self.sftp().rename(src, dst, None)).await
+    }
+
+    /// Copies a local file to `remote`, creating or truncating it.
+    /// Returns the number of bytes written.
+    pub async fn upload(&self, local: &Path, remote: &Path) -> Result<u64, Error> {
+        let mut source = File::open(local).await?;
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+        let mut file = self.open(|| self.sftp().open_mode(remote, flags, FILEMODE as i32, OpenType::File)).await?;
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut sent = 0;
+        loop {
+            let n = source.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            self.driver.write_all(&mut *file, &buf[..n]).await?;
+            sent += n as u64;
+        }
+        file.close().await?;
+        Ok(sent)
+    }
+
+    /// Copies `remote` to a local file, creating or truncating it.
+    /// Returns the number of bytes read.
+    pub async fn download(&self, remote: &Path, local: &Path) -> Result<u64, Error> {
+        let mut file = self.open(|| self.sftp().open_mode(remote, OpenFlags::READ, 0, OpenType::File)).await?;
+        let mut target = File::create(local).await?;
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut received = 0;
+        loop {
+            let n = self.driver.read(&mut *file, &mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            target.write_all(&buf[..n]).await?;
+            received += n as u64;
+        }
+        target.flush().await?;
+        file.close().await?;
+        Ok(received)
+    }
+
+    /// Shuts the subsystem down and waits for the server to close its channel.
+    pub async fn close(mut self) {
+        if let Some(task) = self.shutdown() {
+            let _ = task.await;
+        }
+    }
+
+    /// Starts shutting the subsystem down on a blocking thread. Runs it in place
+    /// when dropped outside a runtime.
+    fn shutdown(&mut self) -> Option<JoinHandle<()>> {
+        let sftp = self.sftp.take()?;
+        tear_down(&self.driver.driver, sftp)
+    }
+}
+
+impl Drop for AsyncSftp {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// An open remote file or directory, only used within one call.
+///
+/// Dropping an `ssh2::File` that is still open waits in blocking mode for the
+/// server, so a handle left open by an early return is closed like the
+/// subsystem itself.
+struct RemoteHandle<'a> {
+    /// Only taken once the handle is closed or dropped.
+    file: Option<ssh2::File>,
+    driver: &'a AsyncDriver,
+}
+
+impl RemoteHandle<'_> {
+    /// Closes the handle, reporting what the server answered.
+    async fn close(mut self) -> Result<(), Error> {
+        let file = self.file.as_mut().expect("file is only taken on close");
+        self.driver.retry(|| file.close()).await.map_err(Error::sftp)?;
+        // Closed for good: dropping it no longer talks to the server.
+        self.file = None;
+        Ok(())
+    }
+}
+
+impl Deref for RemoteHandle<'_> {
+    type Target = ssh2::File;
+
+    fn deref(&self) -> &ssh2::File {
+        self.file.as_ref().expect("file is only taken on close")
+    }
+}
+
+impl DerefMut for RemoteHandle<'_> {
+    fn deref_mut(&mut self) -> &mut ssh2::File {
+        self.file.as_mut().expect("file is only taken on close")
+    }
+}
+
+impl Drop for RemoteHandle<'_> {
+    fn drop(&mut self) {
+        if let Some(file) = self.file.take() {
+            tear_down(&self.driver.driver, file);
+        }
+    }
+}
+
+/// Drops a value whose teardown ssh2 runs in blocking mode, giving up after
+/// `CLOSE_TIMEOUT`. Runs on a blocking thread of the runtime, or in place when
+/// there is no runtime.
+fn tear_down<T: Send + 'static>(driver: &Driver, value: T) -> Option<JoinHandle<()>> {
+    let driver = driver.clone();
+    match Handle::try_current() {
+        Ok(runtime) => Some(runtime.spawn_blocking(move || driver.bounded(|| drop(value)))),
+        Err(_) => {
+            driver.bounded(|| drop(value));
+            None
+        }
+    }
+}
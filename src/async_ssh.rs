@@ -0,0 +1,312 @@
+use crate::async_driver::AsyncDriver;
+use crate::async_forward::{AsyncLocalForward, AsyncRemoteForward, AsyncTunnel};
+use crate::async_sftp::AsyncSftp;
+use crate::driver::{expired, Driver, Transport};
+use crate::error::Error;
+use crate::exec::{self, Chunk, CommandOutput};
+use crate::hostkey::{HostKeyCheck, HostKeyPolicy};
+use crate::options::ConnectOptions;
+use crate::scp::{self, CHUNK_SIZE};
+use crate::{tcp, SSH};
+use ssh2::{Channel, ScpFileStat, Session};
+use std::io;
+use std::path::Path;
+use std::task::Poll;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::ToSocketAddrs;
+
+/// An SSH connection for async code, driven by the Tokio runtime it is used from.
+///
+/// The counterpart of `SSH` for commands, SCP and SFTP transfers and port
+/// forwarding. libssh2 runs in non-blocking mode and tasks wait on the readiness
+/// of the session socket, so no thread is tied up per operation. Jump hosts, proxy
+/// commands, reconnecting and background keepalives are only available on `SSH`.
+pub struct AsyncSSH {
+    driver: Option<AsyncDriver>,
+    host: String,
+    port: u16,
+    host_keys: HostKeyCheck,
+    options: ConnectOptions,
+}
+
+impl AsyncSSH {
+    /// Creates an AsyncSSH object with the host/IP and port. The connection is not established at this point.
+    pub fn new(host: &str, port: u16) -> Self {
+        Self {
+            driver: None,
+            host: tcp::unbracket(host).to_owned(),
+            port,
+            host_keys: HostKeyCheck::default(),
+            options: ConnectOptions::default(),
+        }
+    }
+
+    /// Sets the connect, handshake and operation timeouts. Takes effect for the
+    /// current session too, except for objects already opened on it.
+    pub fn set_connect_options(&mut self, options: ConnectOptions) {
+        self.options = options;
+        if let Some(driver) = &mut self.driver {
+            driver.driver.set_timeout(options.operation_timeout);
+        }
+    }
+
+    /// See `SSH::set_host_key_policy`.
+    pub fn set_host_key_policy(&mut self, policy: HostKeyPolicy) {
+        self.host_keys.policy = policy;
+    }
+
+    /// See `SSH::set_known_hosts_file`.
+    pub fn set_known_hosts_file(&mut self, path: &Path) {
+        self.host_keys.known_hosts = Some(path.to_owned());
+    }
+
+    /// See `SSH::set_record_new_host_keys`.
+    pub fn set_record_new_host_keys(&mut self, record: bool) {
+        self.host_keys.record_new = record;
+    }
+
+    /// Connects to the server, runs the handshake and checks its host key.
+    async fn create_session(&self) -> Result<AsyncDriver, Error> {
+        let socket = tcp::connect_async(&self.host, self.port, &self.options).await?;
+        let waiter = socket.try_clone().map_err(Error::Connect)?;
+        let mut sess = Session::new()?;
+        sess.set_tcp_stream(socket);
+        let mut handshake = sess.clone();
+        let mut driver = Driver::new(sess, Transport::new(waiter, None));
+        driver.set_timeout(self.options.handshake_timeout);
+        let mut driver = AsyncDriver::new(driver)?;
+        driver.retry(|| handshake.handshake()).await.map_err(Error::handshake)?;
+        self.host_keys.verify(&driver.driver.sess, &self.host, self.port)?;
+        driver.driver.set_timeout(self.options.operation_timeout);
+        Ok(driver)
+    }
+
+    /// Initialize connection and authenticate to SSH server
+    pub async fn connect(&mut self, username: &str, pass: &str) -> Result<(), Error> {
+        let driver = self.create_session().await?;
+        let sess = &driver.driver.sess;
+        let res = driver.retry(|| sess.userauth_password(username, pass)).await;
+        SSH::check_auth(sess, "password", res)?;
+        self.driver = Some(driver);
+        Ok(())
+    }
+
+    /// Authenticate using `ssh-agent`, trying each of its identities in turn.
+    pub async fn connect_agent(&mut self, username: &str) -> Result<(), Error> {
+        let driver = self.create_session().await?;
+        let sess = &driver.driver.sess;
+        let res = Self::userauth_agent(&driver, username).await;
+        SSH::check_auth(sess, "publickey", res)?;
+        self.driver = Some(driver);
+        Ok(())
+    }
+
+    /// `Session::userauth_agent` gives up on an identity as soon as it would block.
+    async fn userauth_agent(driver: &AsyncDriver, username: &str) -> Result<(), ssh2::Error> {
+        let mut agent = driver.driver.sess.agent()?;
+        agent.connect()?;
+        agent.list_identities()?;
+        for identity in agent.identities()? {
+            if driver.retry(|| agent.userauth(username, &identity)).await.is_ok() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Authenticate with a private key file, e.g. a deploy key.
+    /// The public key is derived from the private key when `public_key` is `None`.
+    pub async fn connect_with_key(&mut self, username: &str, private_key: &Path, public_key: Option<&Path>, passphrase: Option<&str>) -> Result<(), Error> {
+        let driver = self.create_session().await?;
+        let sess = &driver.driver.sess;
+        let res = driver
+            .retry(|| sess.userauth_pubkey_file(username, public_key, private_key, passphrase))
+            .await;
+        SSH::check_auth(sess, "publickey", res)?;
+        self.driver = Some(driver);
+        Ok(())
+    }
+
+    /// Returns a bool based on status of authentication.
+    pub fn authed(&self) -> bool {
+        self.driver.as_ref().is_some_and(|d| d.driver.is_alive() && d.driver.sess.authenticated())
+    }
+
+    /// The current session, if it is still up.
+    fn driver(&self) -> Result<&AsyncDriver, Error> {
+        match &self.driver {
+            Some(driver) if driver.driver.is_alive() => Ok(driver),
+            Some(_) => Err(Error::ConnectionLost),
+            None => Err(Error::NotConnected),
+        }
+    }
+
+    /// Run a command on the server and return its stdout.
+    /// Use `exec` to also get stderr and the exit status.
+    pub async fn run_command(&self, cmd: &str) -> Result<String, Error> {
+        let output = self.exec(cmd).await?;
+        String::from_utf8(output.stdout).map_err(|e| Error::Io(io::Error::new(io::ErrorKind::InvalidData, e)))
+    }
+
+    /// Run a command on the server, collecting stdout, stderr and how it exited.
+    /// A non-zero exit is not an error; call `check()` on the result for that.
+    pub async fn exec(&self, cmd: &str) -> Result<CommandOutput, Error> {
+        self.exec_stream(cmd).await?.wait().await
+    }
+
+    /// Start a command on the server without waiting for it, to stream its output
+    /// as it arrives and feed its stdin.
+    pub async fn exec_stream(&self, cmd: &str) -> Result<AsyncRemoteProcess, Error> {
+        AsyncRemoteProcess::start(self.driver()?, cmd).await
+    }
+
+    /// Starts an SFTP session for file management and transfers.
+    pub async fn sftp(&self) -> Result<AsyncSftp, Error> {
+        AsyncSftp::start(self.driver()?).await
+    }
+
+    /// SCP a file to the server, keeping its permissions and modification time.
+    pub async fn upload_file(&self, fpath: &Path, dest: &Path) -> Result<(), Error> {
+        let driver = self.driver()?;
+        let mut file = File::open(fpath).await?;
+        let meta = file.metadata().await?;
+        let total = meta.len();
+
+        let sess = &driver.driver.sess;
+        let mut channel = driver
+            .open(|| sess.scp_send(dest, scp::mode(&meta), total, scp::times(&meta)))
+            .await
+            .map_err(Error::scp)?;
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut sent = 0;
+        while sent < total {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                let _ = driver.close(&mut channel).await;
+                return Err(scp::truncated());
+            }
+            let n = n.min((total - sent) as usize);
+            driver.write_all(&mut channel, &buf[..n]).await?;
+            sent += n as u64;
+        }
+        driver.write_all(&mut channel, &scp::END_OF_FILE).await?;
+        driver.retry(|| channel.send_eof()).await.map_err(Error::scp)?;
+        driver.retry(|| channel.wait_eof()).await.map_err(Error::scp)?;
+        let _ = driver.close(&mut channel).await;
+        Ok(())
+    }
+
+    /// Download a file from the server to `local`, giving it the remote file's permissions.
+    pub async fn download_file(&self, remote: &Path, local: &Path) -> Result<ScpFileStat, Error> {
+        let driver = self.driver()?;
+        let sess = &driver.driver.sess;
+        // Only touch the local file once the server has agreed to send the remote one.
+        let (mut channel, stat) = driver.open(|| sess.scp_recv(remote)).await.map_err(Error::scp)?;
+        let mut file = OpenOptions::from(scp::create_options()).open(local).await?;
+        let total = stat.size();
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut received = 0;
+        while received < total {
+            let want = buf.len().min((total - received) as usize);
+            let n = driver.read(&mut channel, &mut buf[..want]).await?;
+            if n == 0 {
+                break;
+            }
+            file.write_all(&buf[..n]).await?;
+            received += n as u64;
+        }
+        let _ = driver.close(&mut channel).await;
+        scp::check_received(received, total)?;
+        file.flush().await?;
+        fs::set_permissions(local, scp::permissions(stat.mode())).await?;
+        Ok(stat)
+    }
+
+    /// Opens a connection to `host:port` as seen from the server, carried over the session.
+    /// `src` is the originating address reported to the server.
+    pub async fn tunnel(&self, host: &str, port: u16, src: Option<(&str, u16)>) -> Result<AsyncTunnel, Error> {
+        AsyncTunnel::open(self.driver()?, host, port, src).await
+    }
+
+    /// Local port forwarding, like `ssh -L`. Connections accepted on `bind_addr` are
+    /// forwarded to `host:port` as seen from the server until the returned handle is stopped.
+    pub async fn local_forward<A: ToSocketAddrs>(&self, bind_addr: A, host: &str, port: u16) -> Result<AsyncLocalForward, Error> {
+        AsyncLocalForward::start(self.driver()?.clone(), bind_addr, host, port).await
+    }
+
+    /// Remote port forwarding, like `ssh -R`. See `SSH::remote_forward`.
+    pub async fn remote_forward<A: ToSocketAddrs>(&self, bind_host: Option<&str>, remote_port: u16, target: A) -> Result<AsyncRemoteForward, Error> {
+        AsyncRemoteForward::start(self.driver()?.clone(), bind_host, remote_port, target).await
+    }
+}
+
+/// A command running on the server, for async code: the counterpart of `RemoteProcess`.
+pub struct AsyncRemoteProcess {
+    channel: Channel,
+    driver: AsyncDriver,
+    buf: Vec<u8>,
+}
+
+impl AsyncRemoteProcess {
+    async fn start(driver: &AsyncDriver, cmd: &str) -> Result<Self, Error> {
+        let sess = &driver.driver.sess;
+        let _opening = driver.opening().await?;
+        let mut channel = driver.retry(|| sess.channel_session()).await.map_err(Error::channel)?;
+        driver.retry(|| channel.exec(cmd)).await.map_err(Error::channel)?;
+        Ok(Self {
+            channel,
+            driver: driver.clone(),
+            buf: vec![0; 32 * 1024],
+        })
+    }
+
+    /// Writes all of `data` to the command's stdin.
+    pub async fn write_stdin(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.driver.write_all(&mut self.channel, data).await?;
+        Ok(())
+    }
+
+    /// Closes the command's stdin.
+    pub async fn send_eof(&mut self) -> Result<(), Error> {
+        let channel = &mut self.channel;
+        self.driver.retry(|| channel.send_eof()).await.map_err(Error::channel)
+    }
+
+    /// Waits for more output. Returns `None` once both streams are finished.
+    pub async fn next_chunk(&mut self) -> Result<Option<Chunk>, Error> {
+        let limit = self.driver.driver.limit();
+        loop {
+            if let Poll::Ready(chunk) = exec::poll_chunk(&mut self.channel, &mut self.buf)? {
+                return Ok(chunk);
+            }
+            if expired(limit) {
+                return Err(Error::Timeout);
+            }
+            self.driver.wait().await;
+        }
+    }
+
+    /// Waits for the command to exit. Output not consumed yet is collected into the result.
+    pub async fn wait(mut self) -> Result<CommandOutput, Error> {
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        while let Some(chunk) = self.next_chunk().await? {
+            exec::collect(chunk, &mut stdout, &mut stderr);
+        }
+        self.driver.close_and_wait(&mut self.channel).await?;
+        let (exit_status, exit_signal) = exec::exit(&self.channel)?;
+        Ok(CommandOutput {
+            stdout,
+            stderr,
+            exit_status,
+            exit_signal,
+        })
+    }
+}
+
+impl Drop for AsyncRemoteProcess {
+    fn drop(&mut self) {
+        // Waiting for the server to acknowledge would block the runtime.
+        let _ = self.channel.close();
+    }
+}
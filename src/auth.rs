@@ -0,0 +1,158 @@
+use crate::error::Error;
+use crate::prompt::{self, Prompter};
+use ssh2::Session;
+use std::path::{Path, PathBuf};
+
+/// One way of proving who we are.
+enum Method {
+    Agent,
+    KeyFile {
+        private_key: PathBuf,
+        public_key: Option<PathBuf>,
+        passphrase: Option<String>,
+    },
+    #[cfg(unix)]
+    KeyData {
+        private_key: String,
+        public_key: Option<String>,
+        passphrase: Option<String>,
+    },
+    KeyboardInteractive(Box<dyn Prompter + Send>),
+    Password(String),
+}
+
+impl Method {
+    /// The SSH name of the method, as listed by the server.
+    fn name(&self) -> &'static str {
+        match self {
+            Method::Agent | Method::KeyFile { .. } => "publickey",
+            #[cfg(unix)]
+            Method::KeyData { .. } => "publickey",
+            Method::KeyboardInteractive(_) => "keyboard-interactive",
+            Method::Password(_) => "password",
+        }
+    }
+
+    /// How the attempt is reported in `Error::AuthFailed`.
+    fn label(&self) -> String {
+        match self {
+            Method::Agent => "publickey (agent)".to_owned(),
+            Method::KeyFile { private_key, .. } => format!("publickey ({})", private_key.display()),
+            #[cfg(unix)]
+            Method::KeyData { .. } => "publickey (in-memory key)".to_owned(),
+            _ => self.name().to_owned(),
+        }
+    }
+
+    fn attempt(&mut self, sess: &Session, username: &str) -> Result<(), ssh2::Error> {
+        match self {
+            Method::Agent => sess.userauth_agent(username),
+            Method::KeyFile {
+                private_key,
+                public_key,
+                passphrase,
+            } => sess.userauth_pubkey_file(username, public_key.as_deref(), private_key, passphrase.as_deref()),
+            #[cfg(unix)]
+            Method::KeyData {
+                private_key,
+                public_key,
+                passphrase,
+            } => sess.userauth_pubkey_memory(username, public_key.as_deref(), private_key, passphrase.as_deref()),
+            Method::KeyboardInteractive(prompter) => {
+                sess.userauth_keyboard_interactive(username, &mut prompt::Adapter(prompter.as_mut()))
+            }
+            Method::Password(pass) => sess.userauth_password(username, pass),
+        }
+    }
+}
+
+/// An ordered list of authentication methods to fall back through.
+///
+/// The server is first asked which methods it accepts, and only those are tried,
+/// each at most once and in the order they were added. A typical chain is agent,
+/// then key files, then keyboard-interactive, then password. Servers that require
+/// several factors are handled by re-reading the accepted methods after each
+/// attempt, so a later method can complete a login an earlier one started.
+#[derive(Default)]
+pub struct AuthChain {
+    methods: Vec<Method>,
+}
+
+impl AuthChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Try the identities held by `ssh-agent`.
+    pub fn agent(mut self) -> Self {
+        self.methods.push(Method::Agent);
+        self
+    }
+
+    /// Try a private key file. The public key is derived when `public_key` is `None`.
+    pub fn key_file(mut self, private_key: &Path, public_key: Option<&Path>, passphrase: Option<&str>) -> Self {
+        self.methods.push(Method::KeyFile {
+            private_key: private_key.to_owned(),
+            public_key: public_key.map(Path::to_owned),
+            passphrase: passphrase.map(str::to_owned),
+        });
+        self
+    }
+
+    /// Try a PEM or OpenSSH private key held in memory.
+    #[cfg(unix)]
+    pub fn key_data(mut self, private_key: &str, public_key: Option<&str>, passphrase: Option<&str>) -> Self {
+        self.methods.push(Method::KeyData {
+            private_key: private_key.to_owned(),
+            public_key: public_key.map(str::to_owned),
+            passphrase: passphrase.map(str::to_owned),
+        });
+        self
+    }
+
+    /// Try keyboard-interactive, answering prompts with `prompter`.
+    pub fn keyboard_interactive<P: Prompter + Send + 'static>(mut self, prompter: P) -> Self {
+        self.methods.push(Method::KeyboardInteractive(Box::new(prompter)));
+        self
+    }
+
+    /// Try a password.
+    pub fn password(mut self, pass: &str) -> Self {
+        self.methods.push(Method::Password(pass.to_owned()));
+        self
+    }
+
+    /// Runs the chain on a session that has completed its handshake.
+    pub(crate) fn authenticate(&mut self, sess: &Session, username: &str) -> Result<(), Error> {
+        let mut offered = offered_methods(sess, username)?;
+        let mut tried = Vec::new();
+        let mut remaining: Vec<&mut Method> = self.methods.iter_mut().collect();
+        while !sess.authenticated() {
+            let next = match remaining.iter().position(|m| offered.iter().any(|o| o == m.name())) {
+                Some(i) => remaining.remove(i),
+                None => return Err(Error::AuthFailed { methods_tried: tried }),
+            };
+            tried.push(next.label());
+            // A failure may still be a partial success, which changes what the server
+            // will accept next. The outcome is judged by `authenticated()` alone.
+            let _ = next.attempt(sess, username);
+            if !sess.authenticated() {
+                // A lost connection or timeout here is not a verdict on the
+                // credentials, so it is reported as such and may be retried.
+                offered = offered_methods(sess, username)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Methods the server will accept for `username` at this point of the login.
+/// Empty if the server let us in without authenticating.
+fn offered_methods(sess: &Session, username: &str) -> Result<Vec<String>, Error> {
+    // libssh2 may report a stale error when the server accepted the "none" probe.
+    match sess.auth_methods(username) {
+        _ if sess.authenticated() => Ok(Vec::new()),
+        Ok(list) => Ok(list.split(',').map(str::to_owned).collect()),
+        Err(e) => Err(Error::from(e)),
+    }
+}
@@ -0,0 +1,639 @@
+use crate::error::Error;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// System-wide client configuration, read after the user's own.
+const SYSTEM_CONFIG: &str = "/etc/ssh/ssh_config";
+
+/// How deeply `Include` directives may nest, as in OpenSSH.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// What the OpenSSH client configuration says about one host alias.
+///
+/// As with `ssh`, the first value found for a keyword wins, except for
+/// `IdentityFile`, which accumulates. `%` tokens and `~` in paths are expanded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostConfig {
+    pub host_name: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub identity_files: Vec<PathBuf>,
+    pub proxy_jump: Option<String>,
+    /// Kept unexpanded, since `%r` depends on the user logging in.
+    pub proxy_command: Option<String>,
+    /// Seconds between keepalives.
+    pub server_alive_interval: Option<u32>,
+    /// Unanswered keepalives before the connection is considered dead.
+    pub server_alive_count_max: Option<u32>,
+    pub connect_timeout: Option<Duration>,
+    /// The raw setting: `any`, `inet` or `inet6`.
+    pub address_family: Option<String>,
+    /// The raw setting: `yes`, `accept-new`, `no`, `off` or `ask`.
+    pub strict_host_key_checking: Option<String>,
+    pub user_known_hosts_file: Option<PathBuf>,
+}
+
+impl HostConfig {
+    /// Resolves `alias` using `~/.ssh/config`, then `/etc/ssh/ssh_config`.
+    pub fn load(alias: &str) -> Result<Self, Error> {
+        let mut files = Vec::new();
+        if let Some(home) = home_dir() {
+            files.push(home.join(".ssh").join("config"));
+        }
+        files.push(PathBuf::from(SYSTEM_CONFIG));
+        Self::from_files(alias, &files)
+    }
+
+    /// Resolves `alias` using the given files in order. Missing files are skipped.
+    pub fn from_files(alias: &str, files: &[PathBuf]) -> Result<Self, Error> {
+        let mut parser = Parser::new(alias);
+        for file in files {
+            parser.read_file(file, 0)?;
+        }
+        Ok(parser.finish())
+    }
+
+    /// Resolves `alias` using configuration text.
+    pub fn parse(alias: &str, text: &str) -> Result<Self, Error> {
+        let mut parser = Parser::new(alias);
+        parser.read_str(text, Path::new("<config>"), 0)?;
+        Ok(parser.finish())
+    }
+}
+
+struct Parser<'a> {
+    alias: &'a str,
+    config: HostConfig,
+}
+
+impl<'a> Parser<'a> {
+    fn new(alias: &'a str) -> Self {
+        Self {
+            alias,
+            config: HostConfig::default(),
+        }
+    }
+
+    fn read_file(&mut self, path: &Path, depth: usize) -> Result<(), Error> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        self.read_str(&text, path, depth)
+    }
+
+    fn read_str(&mut self, text: &str, path: &Path, depth: usize) -> Result<(), Error> {
+        // Settings before the first Host or Match line apply to every host.
+        let mut active = true;
+        for (n, line) in text.lines().enumerate() {
+            let err = |msg: &str| Error::Config(format!("{}:{}: {}", path.display(), n + 1, msg));
+            let (keyword, rest) = match split_line(line) {
+                Some((keyword, rest)) => (keyword.to_ascii_lowercase(), rest),
+                None => continue,
+            };
+            let args = tokenize(rest);
+            match keyword.as_str() {
+                "host" => active = match_patterns(self.alias, args.iter().map(String::as_str)),
+                "match" => active = self.match_block(&args).map_err(err)?,
+                _ if !active => {}
+                "include" => {
+                    if depth >= MAX_INCLUDE_DEPTH {
+                        return Err(err("too many nested Include directives"));
+                    }
+                    for pattern in &args {
+                        for file in include_files(pattern, path)? {
+                            self.read_file(&file, depth + 1)?;
+                        }
+                    }
+                }
+                _ => self.apply(&keyword, rest, &args).map_err(err)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Records a setting unless an earlier line already did. `raw` is the line
+    /// after the keyword, before tokenizing.
+    fn apply(&mut self, keyword: &str, raw: &str, args: &[String]) -> Result<(), &'static str> {
+        let c = &mut self.config;
+        let arg = match args.first() {
+            Some(arg) => arg.clone(),
+            None => return Err("missing argument"),
+        };
+        match keyword {
+            "hostname" if c.host_name.is_none() => c.host_name = Some(arg),
+            "port" if c.port.is_none() => c.port = Some(arg.parse().map_err(|_| "invalid Port")?),
+            "user" if c.user.is_none() => c.user = Some(arg),
+            "identityfile" => c.identity_files.push(PathBuf::from(arg)),
+            // Whichever of ProxyJump and ProxyCommand comes first wins.
+            "proxyjump" if c.proxy_jump.is_none() && c.proxy_command.is_none() => c.proxy_jump = Some(arg),
+            // Handed to the shell as written, quotes included, like OpenSSH does.
+            "proxycommand" if c.proxy_jump.is_none() && c.proxy_command.is_none() => {
+                c.proxy_command = Some(raw.trim_end().to_owned())
+            }
+            "serveraliveinterval" if c.server_alive_interval.is_none() => {
+                c.server_alive_interval = Some(arg.parse().map_err(|_| "invalid ServerAliveInterval")?)
+            }
+            "serveralivecountmax" if c.server_alive_count_max.is_none() => {
+                c.server_alive_count_max = Some(arg.parse().map_err(|_| "invalid ServerAliveCountMax")?)
+            }
+            "connecttimeout" if c.connect_timeout.is_none() && arg != "none" => {
+                let secs = arg.parse().map_err(|_| "invalid ConnectTimeout")?;
+                c.connect_timeout = Some(Duration::from_secs(secs))
+            }
+            "addressfamily" if c.address_family.is_none() => c.address_family = Some(arg.to_ascii_lowercase()),
+            "stricthostkeychecking" if c.strict_host_key_checking.is_none() => {
+                c.strict_host_key_checking = Some(arg.to_ascii_lowercase())
+            }
+            "userknownhostsfile" if c.user_known_hosts_file.is_none() => {
+                c.user_known_hosts_file = Some(PathBuf::from(arg))
+            }
+            // Already set, or not something we act on.
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Evaluates the criteria of a `Match` line. `exec`, `localnetwork` and `tagged`
+    /// are never considered to match.
+    fn match_block(&self, args: &[String]) -> Result<bool, &'static str> {
+        let mut result = true;
+        let mut args = args.iter();
+        while let Some(criterion) = args.next() {
+            let (negate, criterion) = match criterion.strip_prefix('!') {
+                Some(rest) => (true, rest.to_ascii_lowercase()),
+                None => (false, criterion.to_ascii_lowercase()),
+            };
+            let matched = match criterion.as_str() {
+                "all" | "final" => true,
+                "canonical" => false,
+                "host" | "originalhost" | "user" | "localuser" | "exec" | "localnetwork" | "tagged" => {
+                    let patterns = args.next().ok_or("missing argument to Match")?.split(',');
+                    match criterion.as_str() {
+                        "host" => match_patterns(&self.host_name(), patterns),
+                        "originalhost" => match_patterns(self.alias, patterns),
+                        "user" => match_patterns(&self.user(), patterns),
+                        "localuser" => match_patterns(&local_user().unwrap_or_default(), patterns),
+                        _ => false,
+                    }
+                }
+                _ => return Err("unsupported Match criterion"),
+            };
+            if matched == negate {
+                result = false;
+            }
+        }
+        Ok(result)
+    }
+
+    /// The host name as known so far.
+    fn host_name(&self) -> String {
+        match &self.config.host_name {
+            Some(name) => name.replace("%h", self.alias).replace("%%", "%"),
+            None => self.alias.to_owned(),
+        }
+    }
+
+    /// The remote user as known so far, with `%u` and `%%` expanded.
+    fn user(&self) -> String {
+        let local = local_user().unwrap_or_default();
+        match &self.config.user {
+            Some(user) => expand_tokens(user, &[('u', &local)]),
+            None => local,
+        }
+    }
+
+    fn finish(self) -> HostConfig {
+        let host = self.host_name();
+        let user = self.user();
+        let port = self.config.port.unwrap_or(22).to_string();
+        let home = home_dir().map(|h| h.to_string_lossy().into_owned()).unwrap_or_default();
+        let local = local_user().unwrap_or_default();
+        let tokens = [('d', home.as_str()), ('h', &host), ('p', &port), ('r', &user), ('u', &local)];
+        let expand = |path: &Path| expand_path(path, &home, &tokens);
+
+        let mut config = self.config;
+        if config.host_name.is_some() {
+            config.host_name = Some(host.clone());
+        }
+        if config.user.is_some() {
+            config.user = Some(user.clone());
+        }
+        config.identity_files = config.identity_files.iter().map(|p| expand(p)).collect();
+        config.user_known_hosts_file = config.user_known_hosts_file.as_deref().map(expand);
+        if config.proxy_jump.as_deref() == Some("none") {
+            config.proxy_jump = None;
+        }
+        if config.proxy_command.as_deref() == Some("none") {
+            config.proxy_command = None;
+        }
+        config
+    }
+}
+
+/// Splits one ProxyJump hop, `[user@]host[:port]` or `ssh://[user@]host[:port]`,
+/// into its parts. IPv6 addresses with a port are written `[addr]:port`.
+pub(crate) fn parse_jump(hop: &str) -> Result<(Option<&str>, &str, Option<u16>), Error> {
+    let invalid = || Error::Config(format!("invalid ProxyJump host {:?}", hop));
+    let spec = hop.trim();
+    let spec = spec.strip_prefix("ssh://").unwrap_or(spec);
+    let (user, rest) = match spec.rfind('@') {
+        Some(at) => (Some(&spec[..at]), &spec[at + 1..]),
+        None => (None, spec),
+    };
+    let (host, port) = if let Some(bracketed) = rest.strip_prefix('[') {
+        let end = bracketed.find(']').ok_or_else(invalid)?;
+        match &bracketed[end + 1..] {
+            "" => (&bracketed[..end], None),
+            port => (&bracketed[..end], Some(port.strip_prefix(':').ok_or_else(invalid)?)),
+        }
+    } else {
+        match rest.split_once(':') {
+            Some((host, port)) if !port.contains(':') => (host, Some(port)),
+            _ => (rest, None),
+        }
+    };
+    let port = match port {
+        Some(port) => Some(port.parse().map_err(|_| invalid())?),
+        None => None,
+    };
+    if host.is_empty() || user == Some("") {
+        return Err(invalid());
+    }
+    Ok((user, host, port))
+}
+
+/// Splits a line into its keyword and the rest. `None` for blank lines and comments.
+fn split_line(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let end = line.find(|c: char| c.is_whitespace() || c == '=').unwrap_or(line.len());
+    let rest = line[end..].trim_start();
+    let rest = rest.strip_prefix('=').unwrap_or(rest).trim_start();
+    Some((&line[..end], rest))
+}
+
+/// Splits arguments on whitespace, keeping double-quoted strings together.
+fn tokenize(args: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in args.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+/// True if `name` matches a positive pattern and no negated (`!`) one.
+pub(crate) fn match_patterns<'p, I: IntoIterator<Item = &'p str>>(name: &str, patterns: I) -> bool {
+    let name = name.to_ascii_lowercase();
+    let mut matched = false;
+    for pattern in patterns {
+        let pattern = pattern.to_ascii_lowercase();
+        match pattern.strip_prefix('!') {
+            Some(negated) if wildcard(negated.as_bytes(), name.as_bytes()) => return false,
+            Some(_) => {}
+            None => matched |= wildcard(pattern.as_bytes(), name.as_bytes()),
+        }
+    }
+    matched
+}
+
+/// Glob matching with `*` and `?`.
+fn wildcard(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|i| wildcard(rest, &text[i..])),
+        Some((b'?', rest)) => !text.is_empty() && wildcard(rest, &text[1..]),
+        Some((c, rest)) => text.first() == Some(c) && wildcard(rest, &text[1..]),
+    }
+}
+
+/// Files named by an `Include` argument, which may use wildcards in its last
+/// component. Relative paths are taken from the including file's directory.
+fn include_files(pattern: &str, from: &Path) -> Result<Vec<PathBuf>, Error> {
+    let pattern = match (pattern.strip_prefix("~/"), home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => from.parent().unwrap_or_else(|| Path::new("")).join(pattern),
+    };
+    let name = pattern.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    if !name.contains(['*', '?']) {
+        return Ok(vec![pattern]);
+    }
+    let dir = pattern.parent().unwrap_or_else(|| Path::new("."));
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if wildcard(name.as_bytes(), entry.file_name().to_string_lossy().as_bytes()) {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Expands a leading `~` and `%` tokens in a path.
+fn expand_path(path: &Path, home: &str, tokens: &[(char, &str)]) -> PathBuf {
+    let path = path.to_string_lossy();
+    let path = match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("{}{}", home, rest),
+        _ => path.into_owned(),
+    };
+    PathBuf::from(expand_tokens(&path, tokens))
+}
+
+/// Replaces `%x` tokens from `values`, and `%%` with `%`. Unknown tokens are kept.
+pub(crate) fn expand_tokens(text: &str, values: &[(char, &str)]) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => out.push('%'),
+            Some(token) => match values.iter().find(|(t, _)| *t == token) {
+                Some((_, value)) => out.push_str(value),
+                None => {
+                    out.push('%');
+                    out.push(token);
+                }
+            },
+            None => out.push('%'),
+        }
+    }
+    out
+}
+
+/// The user's home directory.
+pub(crate) fn home_dir() -> Option<PathBuf> {
+    env::var_os("HOME").or_else(|| env::var_os("USERPROFILE")).map(PathBuf::from)
+}
+
+/// The name of the local user.
+pub(crate) fn local_user() -> Option<String> {
+    env::var("USER").or_else(|_| env::var("USERNAME")).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(alias: &str, text: &str) -> HostConfig {
+        HostConfig::parse(alias, text).unwrap()
+    }
+
+    fn config_error(alias: &str, text: &str) -> String {
+        match HostConfig::parse(alias, text) {
+            Err(Error::Config(msg)) => msg,
+            other => panic!("expected a config error, got {:?}", other),
+        }
+    }
+
+    /// A directory of its own under the system temp dir, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("sshrs-config-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn write(&self, name: &str, text: &str) -> PathBuf {
+            let path = self.0.join(name);
+            fs::write(&path, text).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn first_value_wins() {
+        let config = parse(
+            "web",
+            "Host web\n  HostName web.internal\n  Port 2200\nHost *\n  HostName other\n  Port 22\n  User deploy\n",
+        );
+        assert_eq!(config.host_name.as_deref(), Some("web.internal"));
+        assert_eq!(config.port, Some(2200));
+        assert_eq!(config.user.as_deref(), Some("deploy"));
+    }
+
+    #[test]
+    fn settings_before_host_apply_to_all() {
+        let config = parse("anything", "User global\nHost other\n  User other\n");
+        assert_eq!(config.user.as_deref(), Some("global"));
+    }
+
+    #[test]
+    fn host_patterns() {
+        let text = "Host *.example.com !secret.example.com\n  Port 2222\nHost db? web\n  User ops\n";
+        assert_eq!(parse("www.example.com", text).port, Some(2222));
+        assert_eq!(parse("secret.example.com", text).port, None);
+        assert_eq!(parse("WWW.EXAMPLE.COM", text).port, Some(2222));
+        assert_eq!(parse("db1", text).user.as_deref(), Some("ops"));
+        assert_eq!(parse("db12", text).user, None);
+        assert_eq!(parse("web", text).user.as_deref(), Some("ops"));
+    }
+
+    #[test]
+    fn keyword_syntax() {
+        let config = parse(
+            "h",
+            "# comment\n\n  PORT=2022\nIdentityFile \"/keys/with space\"\nProxyCommand nc -X connect %h %p\n",
+        );
+        assert_eq!(config.port, Some(2022));
+        assert_eq!(config.identity_files, vec![PathBuf::from("/keys/with space")]);
+        assert_eq!(config.proxy_command.as_deref(), Some("nc -X connect %h %p"));
+    }
+
+    #[test]
+    fn identity_files_accumulate_and_expand() {
+        let home = home_dir().unwrap_or_default();
+        let local = local_user().unwrap_or_default();
+        let config = parse(
+            "alias",
+            "Host alias\n  HostName %h.lan\n  User admin\n  Port 2200\n  IdentityFile ~/.ssh/%h_%r_%p\nHost *\n  IdentityFile /keys/%u-%%-%d\n",
+        );
+        assert_eq!(config.host_name.as_deref(), Some("alias.lan"));
+        assert_eq!(
+            config.identity_files,
+            vec![
+                home.join(".ssh/alias.lan_admin_2200"),
+                PathBuf::from(format!("/keys/{}-%-{}", local, home.display())),
+            ]
+        );
+    }
+
+    #[test]
+    fn user_known_hosts_file_expands() {
+        let home = home_dir().unwrap_or_default();
+        let config = parse("h", "UserKnownHostsFile ~/.ssh/known_%h\n");
+        assert_eq!(config.user_known_hosts_file, Some(home.join(".ssh/known_h")));
+    }
+
+    #[test]
+    fn proxy_command_keeps_quotes() {
+        let config = parse("h", "ProxyCommand sh -c \"nc %h %p\"  \n");
+        assert_eq!(config.proxy_command.as_deref(), Some("sh -c \"nc %h %p\""));
+    }
+
+    #[test]
+    fn proxy_jump_and_command_first_wins() {
+        let config = parse("h", "ProxyJump bastion\nProxyCommand nc %h %p\n");
+        assert_eq!(config.proxy_jump.as_deref(), Some("bastion"));
+        assert_eq!(config.proxy_command, None);
+
+        let config = parse("h", "ProxyCommand none\nProxyJump bastion\n");
+        assert_eq!(config.proxy_jump, None);
+        assert_eq!(config.proxy_command, None);
+    }
+
+    #[test]
+    fn other_settings() {
+        let config = parse(
+            "h",
+            "ServerAliveInterval 15\nServerAliveCountMax 4\nConnectTimeout 7\nAddressFamily INET6\nStrictHostKeyChecking Accept-New\n",
+        );
+        assert_eq!(config.server_alive_interval, Some(15));
+        assert_eq!(config.server_alive_count_max, Some(4));
+        assert_eq!(config.connect_timeout, Some(Duration::from_secs(7)));
+        assert_eq!(config.address_family.as_deref(), Some("inet6"));
+        assert_eq!(config.strict_host_key_checking.as_deref(), Some("accept-new"));
+        assert_eq!(parse("h", "ConnectTimeout none\n").connect_timeout, None);
+    }
+
+    #[test]
+    fn invalid_values_report_the_line() {
+        let msg = config_error("h", "Host h\n  Port http\n");
+        assert!(msg.ends_with(":2: invalid Port"), "{}", msg);
+        assert!(config_error("h", "User\n").ends_with(":1: missing argument"));
+    }
+
+    #[test]
+    fn match_criteria() {
+        let text = "Host short\n  HostName long.example.com\nMatch host *.example.com\n  Port 2200\nMatch originalhost short user admin\n  User root\n";
+        let config = parse("short", text);
+        assert_eq!(config.port, Some(2200));
+        assert_eq!(config.user, None);
+
+        let text = "User admin\nMatch originalhost short user admin\n  Port 2201\n";
+        assert_eq!(parse("short", text).port, Some(2201));
+        assert_eq!(parse("long", text).port, None);
+    }
+
+    #[test]
+    fn match_negation_and_all() {
+        let text = "Match !host prod-*\n  User dev\nMatch all\n  Port 2022\n";
+        let dev = parse("test-1", text);
+        assert_eq!(dev.user.as_deref(), Some("dev"));
+        assert_eq!(dev.port, Some(2022));
+        assert_eq!(parse("prod-1", text).user, None);
+    }
+
+    #[test]
+    fn match_never_matching_criteria() {
+        let text = "Match exec \"true\"\n  Port 1\nMatch canonical\n  Port 2\nMatch tagged x\n  Port 3\n";
+        assert_eq!(parse("h", text).port, None);
+    }
+
+    #[test]
+    fn unsupported_match_criterion() {
+        let msg = config_error("h", "Host h\nMatch address 10.0.0.0/8\n  Port 1\n");
+        assert!(msg.ends_with(":2: unsupported Match criterion"), "{}", msg);
+        let msg = config_error("h", "Match host\n");
+        assert!(msg.ends_with(":1: missing argument to Match"), "{}", msg);
+    }
+
+    #[test]
+    fn include_relative_and_wildcard() {
+        let dir = TempDir::new("include");
+        dir.write("10-web.conf", "Host web\n  HostName web.internal\n");
+        dir.write("20-all.conf", "Host *\n  HostName ignored\n  Port 2200\n");
+        dir.write("skip.txt", "Port 1\n");
+        let main = dir.write("config", "Include *.conf\nHost *\n  User deploy\n");
+        let config = HostConfig::from_files("web", &[main]).unwrap();
+        assert_eq!(config.host_name.as_deref(), Some("web.internal"));
+        assert_eq!(config.port, Some(2200));
+        assert_eq!(config.user.as_deref(), Some("deploy"));
+    }
+
+    #[test]
+    fn include_inside_inactive_host_is_skipped() {
+        let dir = TempDir::new("inactive");
+        dir.write("extra", "Port 2200\n");
+        let main = dir.write("config", "Host other\n  Include extra\n");
+        assert_eq!(HostConfig::from_files("web", &[main]).unwrap().port, None);
+    }
+
+    #[test]
+    fn include_loop_is_an_error() {
+        let dir = TempDir::new("loop");
+        let main = dir.write("config", "Include config\n");
+        match HostConfig::from_files("h", &[main]) {
+            Err(Error::Config(msg)) => assert!(msg.ends_with("too many nested Include directives"), "{}", msg),
+            other => panic!("expected a config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_files_are_skipped() {
+        let dir = TempDir::new("missing");
+        let config = HostConfig::from_files("h", &[dir.0.join("absent")]).unwrap();
+        assert_eq!(config, HostConfig::default());
+    }
+
+    #[test]
+    fn jump_hops() {
+        assert_eq!(parse_jump("bastion").unwrap(), (None, "bastion", None));
+        assert_eq!(parse_jump("ops@bastion:2222").unwrap(), (Some("ops"), "bastion", Some(2222)));
+        assert_eq!(parse_jump("ssh://ops@bastion").unwrap(), (Some("ops"), "bastion", None));
+        assert_eq!(parse_jump(" me@corp@gw:22 ").unwrap(), (Some("me@corp"), "gw", Some(22)));
+        assert_eq!(parse_jump("[2001:db8::1]:2200").unwrap(), (None, "2001:db8::1", Some(2200)));
+        assert_eq!(parse_jump("ops@[::1]").unwrap(), (Some("ops"), "::1", None));
+        assert_eq!(parse_jump("2001:db8::1").unwrap(), (None, "2001:db8::1", None));
+    }
+
+    #[test]
+    fn invalid_jump_hops() {
+        for hop in ["", "@bastion", "bastion:ssh", "bastion:99999", "[::1", "[::1]2200", "ops@"] {
+            assert!(matches!(parse_jump(hop), Err(Error::Config(_))), "{:?}", hop);
+        }
+    }
+
+    #[test]
+    fn token_expansion() {
+        let values = [('h', "host"), ('p', "22")];
+        assert_eq!(expand_tokens("%h:%p", &values), "host:22");
+        assert_eq!(expand_tokens("100%% %x %", &values), "100% %x %");
+    }
+}
@@ -0,0 +1,376 @@
+use crate::error::{Error, LIBSSH2_ERROR_TIMEOUT};
+use crate::forward::Worker;
+use crate::mux::{ChannelLimit, OpenGuard, OpenLock};
+use ssh2::{BlockDirections, Channel, ErrorCode, Session};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// libssh2 returns this code whenever a non-blocking call would have blocked.
+const LIBSSH2_ERROR_EAGAIN: i32 = -37;
+
+/// Upper bound on a single wait for socket readiness. Another thread sharing the
+/// session may consume the data we were waiting for, so never sleep for long.
+pub(crate) const WAIT_SLICE: Duration = Duration::from_millis(10);
+
+/// Message of the errors returned when an operation runs out of time.
+pub(crate) const TIMED_OUT: &str = "timed out waiting for the server";
+
+/// How long to wait for the server to acknowledge closing a channel.
+pub(crate) const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The connection a session runs over.
+pub(crate) struct Transport {
+    /// A second handle on the socket given to libssh2, for waiting on readiness.
+    socket: TcpStream,
+    /// Feeds the socket when the server is reached through a jump host or proxy command.
+    relay: Option<Worker>,
+    /// Set once the connection has been cut on our side.
+    dead: AtomicBool,
+    /// Serializes opens on the session.
+    opening: Arc<OpenLock>,
+}
+
+impl Transport {
+    pub(crate) fn new(socket: TcpStream, relay: Option<Worker>) -> Self {
+        Self {
+            socket,
+            relay,
+            dead: AtomicBool::new(false),
+            opening: Arc::default(),
+        }
+    }
+}
+
+/// The current session of an `SSH` object. Forwards share it so that they carry on
+/// over the new session when the `SSH` object reconnects.
+#[derive(Default)]
+pub(crate) struct Link {
+    current: Mutex<Option<Driver>>,
+    /// Kept across reconnects, like the channels that count against it.
+    pub(crate) channels: Arc<ChannelLimit>,
+}
+
+impl Link {
+    pub(crate) fn get(&self) -> Option<Driver> {
+        self.current.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    pub(crate) fn set(&self, driver: Option<Driver>) {
+        *self.current.lock().unwrap_or_else(PoisonError::into_inner) = driver;
+    }
+
+    /// A live session to use instead of `old`, once the `SSH` object has reconnected.
+    pub(crate) fn replacement(&self, old: &Driver) -> Option<Driver> {
+        self.get().filter(|driver| !driver.same_session(old) && driver.is_alive())
+    }
+}
+
+/// An authenticated session together with the connection it runs over.
+///
+/// Once authenticated the session is switched to non-blocking mode so that it can
+/// be shared between the caller and background tasks such as port forwards.
+/// Every libssh2 call goes through `retry`/`retry_io`, which wait on the socket
+/// until the call can make progress, giving blocking semantics to callers.
+#[derive(Clone)]
+pub(crate) struct Driver {
+    pub(crate) sess: Session,
+    transport: Arc<Transport>,
+    /// How long a single blocking call may wait for the server.
+    timeout: Option<Duration>,
+    /// When the operation this handle was made for must be finished.
+    deadline: Option<Instant>,
+}
+
+impl Driver {
+    /// Takes over an authenticated session. The transport's socket must refer to
+    /// the same connection that was handed to `Session::set_tcp_stream`.
+    pub(crate) fn new(sess: Session, transport: Transport) -> Self {
+        sess.set_blocking(false);
+        // libssh2 already keeps the socket non-blocking; `is_alive` relies on it.
+        let _ = transport.socket.set_nonblocking(true);
+        Self {
+            sess,
+            transport: Arc::new(transport),
+            timeout: None,
+            deadline: None,
+        }
+    }
+
+    /// False once the connection is known to be gone: closed by the server, or
+    /// broken. Does not wait for the server.
+    pub(crate) fn is_alive(&self) -> bool {
+        let transport = &self.transport;
+        if transport.dead.load(Ordering::Relaxed) {
+            return false;
+        }
+        if transport.relay.as_ref().is_some_and(|r| !r.is_running()) {
+            return false;
+        }
+        match transport.socket.peek(&mut [0]) {
+            Ok(0) => false,
+            Ok(_) => true,
+            Err(ref e) => matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted),
+        }
+    }
+
+    /// Cuts the connection, e.g. once the server stopped answering keepalives. Calls
+    /// waiting on it fail instead of waiting forever, and `is_alive` turns false.
+    pub(crate) fn mark_dead(&self) {
+        // Unread data would still make the socket look alive.
+        self.transport.dead.store(true, Ordering::Relaxed);
+        let _ = self.transport.socket.shutdown(Shutdown::Both);
+    }
+
+    /// How long ago the server last sent anything, where the OS keeps track of it.
+    pub(crate) fn silent_for(&self) -> Option<Duration> {
+        last_received(&self.transport.socket)
+    }
+
+    /// Whether both handles drive the same session.
+    pub(crate) fn same_session(&self, other: &Driver) -> bool {
+        Arc::ptr_eq(&self.transport, &other.transport)
+    }
+
+    /// Bounds every blocking call made through this handle and its later clones.
+    pub(crate) fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// A handle for one operation, which must complete within `timeout` (or the
+    /// handle's own timeout) as a whole. Not for objects that outlive the call.
+    pub(crate) fn for_operation(&self, timeout: Option<Duration>) -> Self {
+        let mut driver = self.clone();
+        driver.deadline = timeout.or(self.timeout).map(|t| Instant::now() + t);
+        driver.timeout = None;
+        driver
+    }
+
+    /// The moment a blocking call starting now gives up, if ever.
+    pub(crate) fn limit(&self) -> Option<Instant> {
+        let per_call = self.timeout.map(|t| Instant::now() + t);
+        match (self.deadline, per_call) {
+            (Some(deadline), Some(per_call)) => Some(deadline.min(per_call)),
+            (deadline, per_call) => deadline.or(per_call),
+        }
+    }
+
+    /// Whether libssh2 last blocked on reading, writing or both.
+    pub(crate) fn directions(&self) -> (bool, bool) {
+        match self.sess.block_directions() {
+            BlockDirections::Outbound => (false, true),
+            BlockDirections::Both => (true, true),
+            BlockDirections::Inbound | BlockDirections::None => (true, false),
+        }
+    }
+
+    /// Waits until the session socket is ready in the direction libssh2 last blocked on,
+    /// or until `max` elapses.
+    pub(crate) fn wait(&self, max: Duration) {
+        let (read, write) = self.directions();
+        poll_socket(&self.transport.socket, read, write, max);
+    }
+
+    /// Another handle on the session socket, for an async runtime to watch.
+    #[cfg(all(feature = "async", unix))]
+    pub(crate) fn clone_socket(&self) -> io::Result<TcpStream> {
+        self.transport.socket.try_clone()
+    }
+
+    /// Runs a libssh2 call until it stops reporting that it would block.
+    pub(crate) fn retry<T, F>(&self, mut op: F) -> Result<T, ssh2::Error>
+    where
+        F: FnMut() -> Result<T, ssh2::Error>,
+    {
+        let limit = self.limit();
+        loop {
+            match op() {
+                Err(ref e) if would_block(e) => {
+                    if expired(limit) {
+                        return Err(timed_out());
+                    }
+                    self.wait(WAIT_SLICE)
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Waits until no other open is under way on the session, within the limit of
+    /// a call starting now. Hold the guard until the new channel is fully set up.
+    pub(crate) fn opening(&self) -> Result<OpenGuard, Error> {
+        self.transport.opening.acquire(self.limit()).ok_or(Error::Timeout)
+    }
+
+    /// Like `opening`, but gives up at once if another open is under way.
+    pub(crate) fn try_open(&self) -> Option<OpenGuard> {
+        self.transport.opening.try_acquire()
+    }
+
+    /// `retry` for a call that opens a channel or subsystem in one step.
+    pub(crate) fn open<T, F>(&self, op: F) -> Result<T, ssh2::Error>
+    where
+        F: FnMut() -> Result<T, ssh2::Error>,
+    {
+        let _opening = self.transport.opening.acquire(self.limit()).ok_or_else(timed_out)?;
+        self.retry(op)
+    }
+
+    /// Runs an I/O call on a channel until it stops reporting `WouldBlock`.
+    pub(crate) fn retry_io<T, F>(&self, mut op: F) -> io::Result<T>
+    where
+        F: FnMut() -> io::Result<T>,
+    {
+        let limit = self.limit();
+        loop {
+            match op() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if expired(limit) {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, TIMED_OUT));
+                    }
+                    self.wait(WAIT_SLICE)
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                res => return res,
+            }
+        }
+    }
+
+    /// Blocking `read` on a channel or stream of this session.
+    pub(crate) fn read<R: Read>(&self, reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+        self.retry_io(|| reader.read(buf))
+    }
+
+    /// Blocking `write_all` on a channel or stream of this session.
+    pub(crate) fn write_all<W: Write>(&self, writer: &mut W, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            match self.retry_io(|| writer.write(data))? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => data = &data[n..],
+            }
+        }
+        self.retry_io(|| writer.flush())
+    }
+
+    /// The descriptor of the session socket, for callers that poll it alongside their own.
+    #[cfg(unix)]
+    pub(crate) fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        use std::os::unix::io::AsRawFd;
+
+        self.transport.socket.as_raw_fd()
+    }
+
+    /// Closes a channel and waits for the server to close its end, which it does
+    /// after reporting how a command exited. Waits as long as the operation may take:
+    /// until its limit, or for good without one. Gives up with `Error::Timeout`; any
+    /// other failure means the session broke.
+    pub(crate) fn close_and_wait(&self, channel: &mut Channel) -> Result<(), Error> {
+        self.close_until(channel, self.limit())
+    }
+
+    /// Best-effort `close_and_wait` for a channel that is being discarded: gives up
+    /// after `CLOSE_TIMEOUT`, and callers can ignore the result.
+    pub(crate) fn close(&self, channel: &mut Channel) -> Result<(), Error> {
+        self.close_until(channel, Some(Instant::now() + CLOSE_TIMEOUT))
+    }
+
+    fn close_until(&self, channel: &mut Channel, limit: Option<Instant>) -> Result<(), Error> {
+        loop {
+            match channel.close() {
+                Ok(()) => return Ok(()),
+                Err(ref e) if would_block(e) => {
+                    if expired(limit) {
+                        return Err(Error::Timeout);
+                    }
+                    self.wait(WAIT_SLICE);
+                }
+                Err(_) => return Err(Error::ConnectionLost),
+            }
+        }
+    }
+
+    /// Runs teardown that ssh2 performs in blocking mode (such as dropping an
+    /// `ssh2::Sftp`), giving up after `CLOSE_TIMEOUT` instead of hanging on the server.
+    pub(crate) fn bounded<T, F: FnOnce() -> T>(&self, op: F) -> T {
+        let previous = self.sess.timeout();
+        self.sess.set_timeout(CLOSE_TIMEOUT.as_millis() as u32);
+        let res = op();
+        self.sess.set_timeout(previous);
+        res
+    }
+}
+
+/// True once `limit` has passed.
+pub(crate) fn expired(limit: Option<Instant>) -> bool {
+    limit.is_some_and(|limit| Instant::now() >= limit)
+}
+
+/// The error a libssh2 call gives up with once its limit has passed.
+pub(crate) fn timed_out() -> ssh2::Error {
+    ssh2::Error::new(ErrorCode::Session(LIBSSH2_ERROR_TIMEOUT), TIMED_OUT)
+}
+
+/// True if a non-blocking libssh2 call could not make progress yet.
+pub(crate) fn would_block(err: &ssh2::Error) -> bool {
+    err.code() == ErrorCode::Session(LIBSSH2_ERROR_EAGAIN)
+}
+
+/// Time since data last arrived on a socket, counted by the kernel whether or not
+/// it has been read yet.
+#[cfg(target_os = "linux")]
+fn last_received(socket: &TcpStream) -> Option<Duration> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc == 0 {
+        Some(Duration::from_millis(u64::from(info.tcpi_last_data_recv)))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn last_received(_socket: &TcpStream) -> Option<Duration> {
+    None
+}
+
+/// Polls a socket for readability and/or writability for at most `max`.
+#[cfg(unix)]
+pub(crate) fn poll_socket(socket: &TcpStream, read: bool, write: bool, max: Duration) {
+    use std::os::unix::io::AsRawFd;
+
+    let mut events = 0;
+    if read {
+        events |= libc::POLLIN;
+    }
+    if write {
+        events |= libc::POLLOUT;
+    }
+    let mut fd = libc::pollfd {
+        fd: socket.as_raw_fd(),
+        events,
+        revents: 0,
+    };
+    // Errors (including EINTR) just end the wait early; the caller retries.
+    unsafe {
+        libc::poll(&mut fd, 1, max.as_millis() as libc::c_int);
+    }
+}
+
+/// Polls a socket for readability and/or writability for at most `max`.
+#[cfg(not(unix))]
+pub(crate) fn poll_socket(_socket: &TcpStream, _read: bool, _write: bool, max: Duration) {
+    std::thread::sleep(max.min(Duration::from_millis(1)));
+}
@@ -0,0 +1,215 @@
+use ssh2::ErrorCode;
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+
+/// libssh2 reports an expired `Session::set_timeout` with this code.
+pub(crate) const LIBSSH2_ERROR_TIMEOUT: i32 = -9;
+
+/// libssh2 codes for a connection that broke under a call.
+const LIBSSH2_ERROR_SOCKET_SEND: i32 = -7;
+const LIBSSH2_ERROR_SOCKET_DISCONNECT: i32 = -13;
+const LIBSSH2_ERROR_SOCKET_RECV: i32 = -43;
+
+/// libssh2 codes for credentials the server turned down.
+const LIBSSH2_ERROR_AUTHENTICATION_FAILED: i32 = -18;
+const LIBSSH2_ERROR_PUBLICKEY_UNVERIFIED: i32 = -19;
+
+/// Errors returned by every fallible operation in this crate.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The operation needs an established session but `connect` has not succeeded.
+    NotConnected,
+    /// The connection to the server was lost. Connect again, or set a reconnect policy.
+    ConnectionLost,
+    /// The TCP connection to the server could not be established.
+    Connect(io::Error),
+    /// None of the addresses a host name resolved to could be reached.
+    /// A single address failing is reported as `Connect`.
+    ConnectFailed {
+        host: String,
+        attempts: Vec<(SocketAddr, io::Error)>,
+    },
+    /// The SSH protocol handshake failed.
+    Handshake(ssh2::Error),
+    /// The server rejected every authentication method that was tried.
+    AuthFailed { methods_tried: Vec<String> },
+    /// The server presented a host key that does not match the recorded one.
+    HostKeyMismatch {
+        host: String,
+        expected: String,
+        actual: String,
+    },
+    /// The server presented a host key that the host key policy does not trust.
+    HostKeyRejected { host: String, fingerprint: String },
+    /// Opening or driving a channel failed.
+    Channel(ssh2::Error),
+    /// An SCP transfer failed.
+    Scp(ssh2::Error),
+    /// An SFTP operation failed.
+    Sftp(ssh2::Error),
+    /// A remote command exited unsuccessfully.
+    RemoteExit {
+        status: i32,
+        signal: Option<String>,
+    },
+    /// Any other libssh2 failure.
+    Ssh(ssh2::Error),
+    /// A local I/O failure.
+    Io(io::Error),
+    /// An ssh_config file could not be understood, or lacks a needed setting.
+    Config(String),
+    /// The ProxyCommand could not be started, or exited and took the connection with it.
+    Proxy(String),
+    /// The operation did not complete within its deadline.
+    Timeout,
+}
+
+impl Error {
+    /// Returns true if libssh2 gave up waiting on the server.
+    fn is_ssh_timeout(err: &ssh2::Error) -> bool {
+        err.code() == ErrorCode::Session(LIBSSH2_ERROR_TIMEOUT)
+    }
+
+    /// Wraps a handshake failure, keeping timeouts distinct.
+    pub(crate) fn handshake(err: ssh2::Error) -> Self {
+        if Error::is_ssh_timeout(&err) {
+            Error::Timeout
+        } else {
+            Error::Handshake(err)
+        }
+    }
+
+    /// Wraps a channel failure, keeping timeouts distinct.
+    pub(crate) fn channel(err: ssh2::Error) -> Self {
+        if Error::is_ssh_timeout(&err) {
+            Error::Timeout
+        } else {
+            Error::Channel(err)
+        }
+    }
+
+    /// Wraps a failed authentication attempt. Only a refusal by the server becomes
+    /// `AuthFailed`; a timeout or a dropped connection is reported as such.
+    pub(crate) fn auth(method: &str, err: ssh2::Error) -> Self {
+        match err.code() {
+            ErrorCode::Session(LIBSSH2_ERROR_AUTHENTICATION_FAILED | LIBSSH2_ERROR_PUBLICKEY_UNVERIFIED) => {
+                Error::AuthFailed {
+                    methods_tried: vec![method.to_owned()],
+                }
+            }
+            ErrorCode::Session(LIBSSH2_ERROR_SOCKET_SEND | LIBSSH2_ERROR_SOCKET_DISCONNECT | LIBSSH2_ERROR_SOCKET_RECV) => {
+                Error::ConnectionLost
+            }
+            _ => Error::from(err),
+        }
+    }
+
+    /// Wraps an SCP failure, keeping timeouts distinct.
+    pub(crate) fn scp(err: ssh2::Error) -> Self {
+        if Error::is_ssh_timeout(&err) {
+            Error::Timeout
+        } else {
+            Error::Scp(err)
+        }
+    }
+
+    /// Wraps an SFTP failure, keeping timeouts distinct.
+    pub(crate) fn sftp(err: ssh2::Error) -> Self {
+        if Error::is_ssh_timeout(&err) {
+            Error::Timeout
+        } else {
+            Error::Sftp(err)
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotConnected => write!(f, "not connected to an SSH server"),
+            Error::ConnectionLost => write!(f, "connection to the SSH server was lost"),
+            Error::Connect(e) => write!(f, "failed to connect: {}", e),
+            Error::ConnectFailed { host, attempts } => {
+                write!(f, "failed to connect to {}", host)?;
+                for (i, (addr, e)) in attempts.iter().enumerate() {
+                    write!(f, "{} {}: {}", if i == 0 { ":" } else { ";" }, addr, e)?;
+                }
+                Ok(())
+            }
+            Error::Handshake(e) => write!(f, "SSH handshake failed: {}", e),
+            Error::AuthFailed { methods_tried } if methods_tried.is_empty() => {
+                write!(f, "authentication failed (the server offered none of the configured methods)")
+            }
+            Error::AuthFailed { methods_tried } => write!(
+                f,
+                "authentication failed (tried: {})",
+                methods_tried.join(", ")
+            ),
+            Error::HostKeyMismatch {
+                host,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "host key for {} does not match: expected {}, got {}",
+                host, expected, actual
+            ),
+            Error::HostKeyRejected { host, fingerprint } => {
+                write!(f, "host key {} for {} is not trusted", fingerprint, host)
+            }
+            Error::Channel(e) => write!(f, "channel error: {}", e),
+            Error::Scp(e) => write!(f, "SCP error: {}", e),
+            Error::Sftp(e) => write!(f, "SFTP error: {}", e),
+            Error::RemoteExit {
+                status,
+                signal: Some(signal),
+            } => write!(
+                f,
+                "remote command killed by signal {} (status {})",
+                signal, status
+            ),
+            Error::RemoteExit { status, signal: None } => {
+                write!(f, "remote command exited with status {}", status)
+            }
+            Error::Ssh(e) => write!(f, "SSH error: {}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Config(msg) => write!(f, "invalid SSH configuration: {}", msg),
+            Error::Proxy(msg) => write!(f, "proxy command failed: {}", msg),
+            Error::Timeout => write!(f, "operation timed out"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Connect(e) | Error::Io(e) => Some(e),
+            Error::Handshake(e) | Error::Channel(e) | Error::Scp(e) | Error::Sftp(e) | Error::Ssh(e) => {
+                Some(e)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::TimedOut => Error::Timeout,
+            _ => Error::Io(err),
+        }
+    }
+}
+
+impl From<ssh2::Error> for Error {
+    fn from(err: ssh2::Error) -> Self {
+        if Error::is_ssh_timeout(&err) {
+            Error::Timeout
+        } else {
+            Error::Ssh(err)
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use crate::driver::{expired, Driver, WAIT_SLICE};
+use crate::error::Error;
+use crate::mux::ChannelSlot;
+use ssh2::Channel;
+use std::io::{self, Read};
+use std::task::Poll;
+
+/// Everything a finished remote command produced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// Exit code reported by the server. Meaningless if `exit_signal` is set.
+    pub exit_status: i32,
+    /// Name of the signal that killed the command, without the `SIG` prefix.
+    pub exit_signal: Option<String>,
+}
+
+impl CommandOutput {
+    /// True if the command exited with status 0 and was not killed by a signal.
+    pub fn success(&self) -> bool {
+        self.exit_status == 0 && self.exit_signal.is_none()
+    }
+
+    /// Turns an unsuccessful exit into `Error::RemoteExit`.
+    pub fn check(self) -> Result<Self, Error> {
+        if self.success() {
+            Ok(self)
+        } else {
+            Err(Error::RemoteExit {
+                status: self.exit_status,
+                signal: self.exit_signal,
+            })
+        }
+    }
+}
+
+/// Which of a command's output streams a chunk came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamKind {
+    Stdout,
+    Stderr,
+}
+
+/// A piece of output from a running command, as it arrived.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub stream: StreamKind,
+    pub data: Vec<u8>,
+}
+
+/// A command running on the server, with its stdin and output streams open.
+///
+/// Output is consumed either chunk by chunk through the `Iterator` impl or line
+/// by line with `for_each_line`. `wait` collects whatever output is left along
+/// with the exit status.
+pub struct RemoteProcess {
+    channel: Channel,
+    driver: Driver,
+    buf: Vec<u8>,
+    _slot: ChannelSlot,
+}
+
+impl RemoteProcess {
+    pub(crate) fn start(driver: &Driver, cmd: &str, slot: ChannelSlot) -> Result<Self, Error> {
+        Ok(Self {
+            channel: start(driver, cmd)?,
+            driver: driver.clone(),
+            buf: vec![0; 32 * 1024],
+            _slot: slot,
+        })
+    }
+
+    /// Writes all of `data` to the command's stdin. Commands that echo their input
+    /// may stop reading once their output is not being consumed, so interleave large
+    /// writes with reading output.
+    pub fn write_stdin(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.driver.write_all(&mut self.channel, data)?;
+        Ok(())
+    }
+
+    /// Closes the command's stdin.
+    pub fn send_eof(&mut self) -> Result<(), Error> {
+        let channel = &mut self.channel;
+        self.driver.retry(|| channel.send_eof()).map_err(Error::channel)
+    }
+
+    /// Blocks until more output arrives. Returns `None` once both streams are finished.
+    pub fn next_chunk(&mut self) -> Result<Option<Chunk>, Error> {
+        let limit = self.driver.limit();
+        loop {
+            if let Poll::Ready(chunk) = poll_chunk(&mut self.channel, &mut self.buf)? {
+                return Ok(chunk);
+            }
+            if expired(limit) {
+                return Err(Error::Timeout);
+            }
+            self.driver.wait(WAIT_SLICE);
+        }
+    }
+
+    /// Calls `f` with every complete line of output as it arrives, until both streams
+    /// are finished. Line endings are stripped and invalid UTF-8 is replaced. A final
+    /// line without a trailing newline is delivered at the end.
+    pub fn for_each_line<F>(&mut self, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(StreamKind, &str),
+    {
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        while let Some(chunk) = self.next_chunk()? {
+            let pending = match chunk.stream {
+                StreamKind::Stdout => &mut stdout,
+                StreamKind::Stderr => &mut stderr,
+            };
+            pending.extend_from_slice(&chunk.data);
+            while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = pending.drain(..=end).collect();
+                f(chunk.stream, &trim_line(&line));
+            }
+        }
+        for (stream, rest) in [(StreamKind::Stdout, stdout), (StreamKind::Stderr, stderr)] {
+            if !rest.is_empty() {
+                f(stream, &trim_line(&rest));
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for the command to exit. Output not consumed yet is collected into the result.
+    pub fn wait(mut self) -> Result<CommandOutput, Error> {
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        while let Some(chunk) = self.next_chunk()? {
+            collect(chunk, &mut stdout, &mut stderr);
+        }
+        let (exit_status, exit_signal) = finish(&self.driver, &mut self.channel)?;
+        Ok(CommandOutput {
+            stdout,
+            stderr,
+            exit_status,
+            exit_signal,
+        })
+    }
+}
+
+impl Iterator for RemoteProcess {
+    type Item = Result<Chunk, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().transpose()
+    }
+}
+
+impl Drop for RemoteProcess {
+    fn drop(&mut self) {
+        let _ = self.driver.close(&mut self.channel);
+    }
+}
+
+/// Decodes a line, dropping its `\n` or `\r\n` terminator.
+fn trim_line(line: &[u8]) -> String {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    String::from_utf8_lossy(line).into_owned()
+}
+
+/// Opens a session channel and starts `cmd` on it.
+pub(crate) fn start(driver: &Driver, cmd: &str) -> Result<Channel, Error> {
+    let _opening = driver.opening()?;
+    let mut channel = driver.retry(|| driver.sess.channel_session()).map_err(Error::channel)?;
+    driver.retry(|| channel.exec(cmd)).map_err(Error::channel)?;
+    Ok(channel)
+}
+
+/// Runs `cmd` to completion, collecting stdout and stderr side by side so that
+/// neither stream can stall the other.
+pub(crate) fn run(driver: &Driver, cmd: &str, slot: ChannelSlot) -> Result<CommandOutput, Error> {
+    RemoteProcess::start(driver, cmd, slot)?.wait()
+}
+
+/// Closes a channel whose output has been drained and collects how the command ended.
+/// Fails if the close did not complete, since the exit status may never have arrived.
+pub(crate) fn finish(driver: &Driver, channel: &mut Channel) -> Result<(i32, Option<String>), Error> {
+    driver.close_and_wait(channel)?;
+    exit(channel)
+}
+
+/// How the command on a closed channel ended. libssh2 reports status 0 if the
+/// server sent none, so only call this once `close` has succeeded.
+pub(crate) fn exit(channel: &Channel) -> Result<(i32, Option<String>), Error> {
+    let status = channel.exit_status().map_err(Error::channel)?;
+    let signal = channel.exit_signal().map_err(Error::channel)?.exit_signal;
+    Ok((status, signal))
+}
+
+/// The next piece of output that has already arrived, `Ready(None)` once both
+/// streams are finished, or `Pending` if there is nothing to read yet.
+pub(crate) fn poll_chunk(channel: &mut Channel, buf: &mut [u8]) -> io::Result<Poll<Option<Chunk>>> {
+    let mut data = Vec::new();
+    if read_available(channel, buf, &mut data)? {
+        return Ok(Poll::Ready(Some(Chunk {
+            stream: StreamKind::Stdout,
+            data,
+        })));
+    }
+    if read_available(&mut channel.stderr(), buf, &mut data)? {
+        return Ok(Poll::Ready(Some(Chunk {
+            stream: StreamKind::Stderr,
+            data,
+        })));
+    }
+    if channel.eof() {
+        return Ok(Poll::Ready(None));
+    }
+    Ok(Poll::Pending)
+}
+
+/// Adds a chunk to the output collected for its stream.
+pub(crate) fn collect(chunk: Chunk, stdout: &mut Vec<u8>, stderr: &mut Vec<u8>) {
+    match chunk.stream {
+        StreamKind::Stdout => stdout.extend_from_slice(&chunk.data),
+        StreamKind::Stderr => stderr.extend_from_slice(&chunk.data),
+    }
+}
+
+/// Appends whatever a stream has buffered to `out` without blocking.
+/// Returns whether anything was read.
+fn read_available<R: Read>(stream: &mut R, buf: &mut [u8], out: &mut Vec<u8>) -> io::Result<bool> {
+    match stream.read(buf) {
+        Ok(n) => {
+            out.extend_from_slice(&buf[..n]);
+            Ok(n > 0)
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    }
+}
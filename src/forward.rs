@@ -0,0 +1,696 @@
+use crate::driver::{expired, would_block, Driver, Link};
+use crate::error::Error;
+use crate::mux::{ChannelSlot, OpenGuard};
+use ssh2::{Channel, Listener};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How long a forwarding loop sleeps on the session socket when nothing moved.
+const IDLE_WAIT: Duration = Duration::from_millis(5);
+
+/// A `direct-tcpip` channel to a host reachable from the server.
+/// Reads and writes block until they complete.
+pub struct Tunnel {
+    channel: Channel,
+    driver: Driver,
+    _slot: ChannelSlot,
+}
+
+impl Tunnel {
+    pub(crate) fn open(driver: &Driver, host: &str, port: u16, src: Option<(&str, u16)>, slot: ChannelSlot) -> Result<Self, Error> {
+        Ok(Self {
+            channel: direct_tcpip(driver, host, port, src)?,
+            driver: driver.clone(),
+            _slot: slot,
+        })
+    }
+
+    /// Tells the far end that nothing more will be written.
+    pub fn send_eof(&mut self) -> Result<(), Error> {
+        let channel = &mut self.channel;
+        self.driver.retry(|| channel.send_eof()).map_err(Error::channel)
+    }
+}
+
+impl Read for Tunnel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.driver.read(&mut self.channel, buf)
+    }
+}
+
+impl Write for Tunnel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let channel = &mut self.channel;
+        self.driver.retry_io(|| channel.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let channel = &mut self.channel;
+        self.driver.retry_io(|| channel.flush())
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        let _ = self.driver.close(&mut self.channel);
+    }
+}
+
+/// Opens a `direct-tcpip` channel, waiting for the server to answer.
+pub(crate) fn direct_tcpip(driver: &Driver, host: &str, port: u16, src: Option<(&str, u16)>) -> Result<Channel, Error> {
+    driver
+        .open(|| driver.sess.channel_direct_tcpip(host, port, src))
+        .map_err(Error::channel)
+}
+
+/// A `direct-tcpip` open that a forwarding loop drives one step per pass, so its
+/// other connections keep moving while the server reaches the target.
+pub(crate) struct PendingOpen<T> {
+    host: String,
+    port: u16,
+    peer: SocketAddr,
+    client: T,
+    slot: ChannelSlot,
+    limit: Option<Instant>,
+    _opening: OpenGuard,
+}
+
+impl<T> PendingOpen<T> {
+    /// Starts opening a channel to `host:port` for `client`, connected from `peer`.
+    pub(crate) fn new(driver: &Driver, (slot, opening): (ChannelSlot, OpenGuard), host: &str, port: u16, client: T, peer: SocketAddr) -> Self {
+        Self {
+            host: host.to_owned(),
+            port,
+            peer,
+            client,
+            slot,
+            limit: driver.limit(),
+            _opening: opening,
+        }
+    }
+
+    /// Moves the open along. None while the server has not answered yet.
+    pub(crate) fn poll(&self, driver: &Driver) -> Option<Result<Channel, Error>> {
+        let src = self.peer.ip().to_string();
+        match driver.sess.channel_direct_tcpip(&self.host, self.port, Some((&src, self.peer.port()))) {
+            Err(ref e) if would_block(e) && !expired(self.limit) => None,
+            Err(ref e) if would_block(e) => Some(Err(Error::Timeout)),
+            res => Some(res.map_err(Error::channel)),
+        }
+    }
+
+    /// Gives back the client, its address and its channel slot.
+    pub(crate) fn finish(self) -> (T, SocketAddr, ChannelSlot) {
+        (self.client, self.peer, self.slot)
+    }
+}
+
+/// A channel slot and the session's open lock, if both are free right now.
+pub(crate) fn ready_to_open(link: &Link, driver: &Driver) -> Option<(ChannelSlot, OpenGuard)> {
+    let slot = link.channels.try_acquire()?;
+    Some((slot, driver.try_open()?))
+}
+
+/// Carries a loopback TCP connection over a `direct-tcpip` channel to `host:port`, so
+/// that another session can run over the channel. Returns the local end to hand to
+/// libssh2 and the worker relaying it, which stops when either end closes.
+pub(crate) fn relay(driver: Driver, host: &str, port: u16) -> Result<(TcpStream, Worker), Error> {
+    let (socket, local) = loopback_pair()?;
+    let peer = socket.local_addr()?;
+    let channel = direct_tcpip(&driver, host, port, None)?;
+    let worker = Worker::spawn(move |shared| {
+        local.set_nonblocking(true)?;
+        let mut pipe = Pipe::new(local, channel, peer, shared, None);
+        let mut buf = vec![0; 32 * 1024];
+        let res = loop {
+            match pipe.pump(&mut buf) {
+                _ if shared.stopped() || pipe.is_done() => break Ok(()),
+                Ok(true) => {}
+                Ok(false) => pipe.idle(&driver),
+                Err(e) => break Err(e.into()),
+            }
+        };
+        pipe.close(&driver);
+        res
+    });
+    Ok((socket, worker))
+}
+
+/// A connected pair of loopback TCP sockets, for handing one end to libssh2.
+pub(crate) fn loopback_pair() -> io::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let socket = TcpStream::connect(listener.local_addr()?)?;
+    let (other, peer) = listener.accept()?;
+    // Any local process could have connected to the listener before us.
+    if peer != socket.local_addr()? {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "unexpected connection to relay socket"));
+    }
+    Ok((socket, other))
+}
+
+/// Snapshot of the traffic on one forwarded connection.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionStats {
+    /// Address of the local end of the connection.
+    pub peer: SocketAddr,
+    /// Bytes carried from the local socket to the SSH channel.
+    pub bytes_sent: u64,
+    /// Bytes carried from the SSH channel to the local socket.
+    pub bytes_received: u64,
+    /// False once both directions have been shut down.
+    pub open: bool,
+}
+
+/// Live counters behind a `ConnectionStats` snapshot.
+#[derive(Debug)]
+pub(crate) struct Counters {
+    peer: SocketAddr,
+    sent: AtomicU64,
+    received: AtomicU64,
+    open: AtomicBool,
+}
+
+impl Counters {
+    fn snapshot(&self) -> ConnectionStats {
+        ConnectionStats {
+            peer: self.peer,
+            bytes_sent: self.sent.load(Ordering::Relaxed),
+            bytes_received: self.received.load(Ordering::Relaxed),
+            open: self.open.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// State shared between a forward handle and its background thread.
+#[derive(Default)]
+pub(crate) struct Shared {
+    stop: AtomicBool,
+    connections: Mutex<Vec<Arc<Counters>>>,
+}
+
+impl Shared {
+    pub(crate) fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn stats(&self) -> Vec<ConnectionStats> {
+        self.connections
+            .lock()
+            .map(|conns| conns.iter().map(|c| c.snapshot()).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Background thread running a forward, stopped and joined on drop.
+pub(crate) struct Worker {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<Result<(), Error>>>,
+}
+
+impl Worker {
+    pub(crate) fn spawn<F>(f: F) -> Self
+    where
+        F: FnOnce(&Shared) -> Result<(), Error> + Send + 'static,
+    {
+        let shared = Arc::new(Shared::default());
+        let inner = Arc::clone(&shared);
+        let thread = thread::spawn(move || f(&inner));
+        Self {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> Vec<ConnectionStats> {
+        self.shared.stats()
+    }
+
+    pub(crate) fn is_running(&self) -> bool {
+        self.thread.as_ref().is_some_and(|t| !t.is_finished())
+    }
+
+    /// Waits up to `max` for the thread to end on its own and returns the error it
+    /// ended with. `None` if it is still running or ended cleanly.
+    pub(crate) fn failure(mut self, max: Duration) -> Option<Error> {
+        let deadline = Instant::now() + max;
+        while self.is_running() && Instant::now() < deadline {
+            thread::sleep(IDLE_WAIT);
+        }
+        if self.is_running() {
+            return None;
+        }
+        self.stop().err()
+    }
+
+    /// Signals the thread to stop and returns the error that ended it, if any.
+    pub(crate) fn stop(&mut self) -> Result<(), Error> {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        match self.thread.take() {
+            Some(thread) => thread.join().unwrap_or(Ok(())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+/// Copies bytes between a local TCP connection and an SSH channel.
+pub(crate) struct Pipe {
+    local: TcpStream,
+    channel: Channel,
+    /// Read from `local`, not yet written to `channel`.
+    upstream: Vec<u8>,
+    /// Read from `channel`, not yet written to `local`.
+    downstream: Vec<u8>,
+    local_eof: bool,
+    eof_sent: bool,
+    remote_eof: bool,
+    local_shut: bool,
+    counters: Arc<Counters>,
+    /// Counts the channel against the session's limit. Relays for jump hosts do not count.
+    _slot: Option<ChannelSlot>,
+}
+
+impl Pipe {
+    /// Starts piping and registers the connection's counters with `shared`.
+    /// `local` must already be in non-blocking mode.
+    pub(crate) fn new(local: TcpStream, channel: Channel, peer: SocketAddr, shared: &Shared, slot: Option<ChannelSlot>) -> Self {
+        let counters = Arc::new(Counters {
+            peer,
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            open: AtomicBool::new(true),
+        });
+        if let Ok(mut conns) = shared.connections.lock() {
+            conns.push(Arc::clone(&counters));
+        }
+        Self {
+            local,
+            channel,
+            upstream: Vec::new(),
+            downstream: Vec::new(),
+            local_eof: false,
+            eof_sent: false,
+            remote_eof: false,
+            local_shut: false,
+            counters,
+            _slot: slot,
+        }
+    }
+
+    /// True once both directions have reached EOF and been flushed.
+    pub(crate) fn is_done(&self) -> bool {
+        self.eof_sent && self.local_shut
+    }
+
+    /// Moves whatever data is ready in either direction without blocking.
+    /// Returns whether anything happened.
+    pub(crate) fn pump(&mut self, buf: &mut [u8]) -> io::Result<bool> {
+        let mut progress = false;
+
+        // Local socket -> channel
+        if self.upstream.is_empty() && !self.local_eof {
+            match ready(self.local.read(buf))? {
+                Some(0) => self.local_eof = true,
+                Some(n) => self.upstream.extend_from_slice(&buf[..n]),
+                None => {}
+            }
+            progress |= self.local_eof || !self.upstream.is_empty();
+        }
+        if !self.upstream.is_empty() {
+            if let Some(n) = ready(self.channel.write(&self.upstream))? {
+                self.upstream.drain(..n);
+                self.counters.sent.fetch_add(n as u64, Ordering::Relaxed);
+                progress = true;
+            }
+        }
+        if self.local_eof
+            && self.upstream.is_empty()
+            && !self.eof_sent
+            && ready(self.channel.send_eof().map_err(io::Error::from))?.is_some()
+        {
+            self.eof_sent = true;
+            progress = true;
+        }
+
+        // Channel -> local socket
+        if self.downstream.is_empty() && !self.remote_eof {
+            match ready(self.channel.read(buf))? {
+                Some(0) => self.remote_eof = true,
+                Some(n) => self.downstream.extend_from_slice(&buf[..n]),
+                None => {}
+            }
+            progress |= self.remote_eof || !self.downstream.is_empty();
+        }
+        if !self.downstream.is_empty() {
+            if let Some(n) = ready(self.local.write(&self.downstream))? {
+                self.downstream.drain(..n);
+                self.counters.received.fetch_add(n as u64, Ordering::Relaxed);
+                progress = true;
+            }
+        }
+        if self.remote_eof && self.downstream.is_empty() && !self.local_shut {
+            let _ = self.local.shutdown(Shutdown::Write);
+            self.local_shut = true;
+            progress = true;
+        }
+
+        Ok(progress)
+    }
+
+    /// Sleeps until the local socket or the session socket has data, or a short
+    /// interval passes. Keeps latency down when the pipe is the only traffic.
+    #[cfg(unix)]
+    pub(crate) fn idle(&self, driver: &Driver) {
+        use std::os::unix::io::AsRawFd;
+
+        let mut fds = [
+            libc::pollfd {
+                fd: driver.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.local.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        // A local socket at EOF stays readable, so stop watching it.
+        let watched = if self.local_eof || !self.upstream.is_empty() { 1 } else { 2 };
+        unsafe {
+            libc::poll(fds.as_mut_ptr(), watched, IDLE_WAIT.as_millis() as libc::c_int);
+        }
+    }
+
+    /// Sleeps until the session socket has data or a short interval passes.
+    #[cfg(not(unix))]
+    pub(crate) fn idle(&self, driver: &Driver) {
+        idle(driver);
+    }
+
+    /// Tears the connection down in both directions.
+    pub(crate) fn close(mut self, driver: &Driver) {
+        let _ = self.local.shutdown(Shutdown::Both);
+        let _ = driver.close(&mut self.channel);
+        self.counters.open.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Maps `WouldBlock` to `None` so callers can skip a direction that is not ready.
+fn ready<T>(res: io::Result<T>) -> io::Result<Option<T>> {
+    match res {
+        Ok(v) => Ok(Some(v)),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Pumps every pipe once, closing finished or failed ones. Returns whether anything moved.
+pub(crate) fn pump_all(driver: &Driver, pipes: &mut Vec<Pipe>, buf: &mut [u8]) -> bool {
+    let mut progress = false;
+    let mut i = 0;
+    while i < pipes.len() {
+        match pipes[i].pump(buf) {
+            Ok(moved) if !pipes[i].is_done() => {
+                progress |= moved;
+                i += 1;
+            }
+            _ => {
+                pipes.swap_remove(i).close(driver);
+                progress = true;
+            }
+        }
+    }
+    progress
+}
+
+/// Starts piping a newly accepted connection. A client socket that cannot be
+/// set up only costs this connection: its channel is closed and the caller
+/// carries on serving the others.
+pub(crate) fn start_pipe(
+    driver: &Driver,
+    pipes: &mut Vec<Pipe>,
+    stream: TcpStream,
+    mut channel: Channel,
+    peer: SocketAddr,
+    shared: &Shared,
+    slot: ChannelSlot,
+) {
+    match stream.set_nonblocking(true) {
+        Ok(()) => pipes.push(Pipe::new(stream, channel, peer, shared, Some(slot))),
+        Err(_) => {
+            let _ = driver.close(&mut channel);
+        }
+    }
+}
+
+/// Sleeps until the session socket has data or a short interval passes.
+pub(crate) fn idle(driver: &Driver) {
+    driver.wait(IDLE_WAIT);
+}
+
+/// What a forward found out about its session.
+pub(crate) enum Session {
+    /// Still usable.
+    Same,
+    /// The `SSH` object reconnected; carry on over the new session.
+    Switched,
+    /// Gone, and not replaced yet.
+    Lost,
+}
+
+/// Checks that `driver` is still usable. Once its connection is gone the pipes over
+/// it are closed, and `driver` is switched to the session the `SSH` object
+/// reconnected with, if it has.
+pub(crate) fn follow(link: &Link, driver: &mut Driver, pipes: &mut Vec<Pipe>) -> Session {
+    if driver.is_alive() {
+        return Session::Same;
+    }
+    for pipe in pipes.drain(..) {
+        pipe.close(driver);
+    }
+    match link.replacement(driver) {
+        Some(next) => {
+            *driver = next;
+            Session::Switched
+        }
+        None => {
+            // A dead socket always polls ready, so sleep instead of waiting on it.
+            thread::sleep(IDLE_WAIT);
+            Session::Lost
+        }
+    }
+}
+
+/// Local port forwarding, the equivalent of `ssh -L`.
+///
+/// Every connection accepted on the local listener gets its own `direct-tcpip`
+/// channel to the target. Forwarding runs on a background thread until `stop`
+/// is called or the handle is dropped, and carries on after the `SSH` object reconnects.
+pub struct LocalForward {
+    local_addr: SocketAddr,
+    worker: Worker,
+}
+
+impl LocalForward {
+    pub(crate) fn start<A: ToSocketAddrs>(link: Arc<Link>, driver: Driver, bind_addr: A, host: &str, port: u16) -> Result<Self, Error> {
+        let listener = TcpListener::bind(bind_addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let host = host.to_owned();
+        let worker = Worker::spawn(move |shared| run_local(&link, driver, &listener, &host, port, shared));
+        Ok(Self { local_addr, worker })
+    }
+
+    /// The address the local listener is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Byte counters for every connection forwarded so far.
+    pub fn connections(&self) -> Vec<ConnectionStats> {
+        self.worker.stats()
+    }
+
+    /// False once the forwarding thread has exited.
+    pub fn is_running(&self) -> bool {
+        self.worker.is_running()
+    }
+
+    /// Stops forwarding, closing every open connection.
+    pub fn stop(mut self) -> Result<(), Error> {
+        self.worker.stop()
+    }
+}
+
+fn run_local(link: &Link, mut driver: Driver, listener: &TcpListener, host: &str, port: u16, shared: &Shared) -> Result<(), Error> {
+    let mut pipes = Vec::new();
+    let mut pending = None;
+    let mut buf = vec![0; 32 * 1024];
+    while !shared.stopped() {
+        // While the session is down, clients wait in the listen backlog. An open
+        // under way goes down with the old session, dropping its client.
+        match follow(link, &mut driver, &mut pipes) {
+            Session::Same => {}
+            Session::Switched => pending = None,
+            Session::Lost => {
+                pending = None;
+                continue;
+            }
+        }
+        let driver = &driver;
+        let mut progress = false;
+        // One open at a time. At the channel limit, or while another open is under
+        // way on the session, clients wait in the listen backlog too.
+        if let Some(ready) = pending.is_none().then(|| ready_to_open(link, driver)).flatten() {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    progress = true;
+                    pending = Some(PendingOpen::new(driver, ready, host, port, stream, peer));
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if let Some(opened) = pending.as_ref().and_then(|open| open.poll(driver)) {
+            progress = true;
+            let (stream, peer, slot) = pending.take().expect("polled above").finish();
+            // A refused channel only affects this client; keep serving the others.
+            if let Ok(channel) = opened {
+                start_pipe(driver, &mut pipes, stream, channel, peer, shared, slot);
+            }
+        }
+        progress |= pump_all(driver, &mut pipes, &mut buf);
+        if !progress {
+            idle(driver);
+        }
+    }
+    for pipe in pipes {
+        pipe.close(&driver);
+    }
+    Ok(())
+}
+
+/// Remote port forwarding, the equivalent of `ssh -R`.
+///
+/// The server listens on our behalf and every connection it accepts arrives as a
+/// `forwarded-tcpip` channel, which is connected to the local target. Forwarding
+/// runs on a background thread until `stop` is called or the handle is dropped. After
+/// the `SSH` object reconnects, the same port is requested again on the new session.
+pub struct RemoteForward {
+    remote_port: u16,
+    worker: Worker,
+}
+
+impl RemoteForward {
+    pub(crate) fn start<A: ToSocketAddrs>(
+        link: Arc<Link>,
+        driver: Driver,
+        bind_host: Option<&str>,
+        remote_port: u16,
+        target: A,
+    ) -> Result<Self, Error> {
+        let target: Vec<SocketAddr> = target.to_socket_addrs()?.collect();
+        let (listener, remote_port) = listen(&driver, bind_host, remote_port)?;
+        let bind_host = bind_host.map(str::to_owned);
+        let worker = Worker::spawn(move |shared| {
+            run_remote(&link, driver, listener, bind_host.as_deref(), remote_port, &target, shared)
+        });
+        Ok(Self { remote_port, worker })
+    }
+
+    /// The port the server is listening on. This is the port it chose when 0 was requested.
+    pub fn remote_port(&self) -> u16 {
+        self.remote_port
+    }
+
+    /// Byte counters for every connection forwarded so far.
+    pub fn connections(&self) -> Vec<ConnectionStats> {
+        self.worker.stats()
+    }
+
+    /// False once the forwarding thread has exited.
+    pub fn is_running(&self) -> bool {
+        self.worker.is_running()
+    }
+
+    /// Stops forwarding, closing every open connection and cancelling the remote listener.
+    pub fn stop(mut self) -> Result<(), Error> {
+        self.worker.stop()
+    }
+}
+
+/// Asks the server to listen on `bind_host:remote_port` for us.
+fn listen(driver: &Driver, bind_host: Option<&str>, remote_port: u16) -> Result<(Listener, u16), Error> {
+    driver
+        .open(|| driver.sess.channel_forward_listen(remote_port, bind_host, None))
+        .map_err(Error::channel)
+}
+
+fn run_remote(
+    link: &Link,
+    mut driver: Driver,
+    mut listener: Listener,
+    bind_host: Option<&str>,
+    remote_port: u16,
+    target: &[SocketAddr],
+    shared: &Shared,
+) -> Result<(), Error> {
+    // Connecting to the target can take until the OS gives up, so each connection
+    // is made on its own thread and handed back here once it is up.
+    let (tx, rx) = mpsc::channel();
+    let mut pipes = Vec::new();
+    let mut buf = vec![0; 32 * 1024];
+    while !shared.stopped() {
+        match follow(link, &mut driver, &mut pipes) {
+            Session::Same => {}
+            Session::Switched => listener = listen(&driver, bind_host, remote_port)?.0,
+            Session::Lost => continue,
+        }
+        let driver = &driver;
+        let mut progress = false;
+        // At the channel limit, connections wait in libssh2's queue.
+        if let Some(slot) = link.channels.try_acquire() {
+            match listener.accept() {
+                Ok(channel) => {
+                    progress = true;
+                    let tx = tx.clone();
+                    let target = target.to_vec();
+                    thread::spawn(move || {
+                        let stream = TcpStream::connect(&target[..]).and_then(|stream| Ok((stream.peer_addr()?, stream)));
+                        let _ = tx.send((stream, channel, slot));
+                    });
+                }
+                Err(ref e) if would_block(e) => {}
+                Err(e) => return Err(Error::channel(e)),
+            }
+        }
+        while let Ok((stream, mut channel, slot)) = rx.try_recv() {
+            progress = true;
+            // An unreachable target only affects this connection; keep serving the others.
+            match stream {
+                Ok((peer, stream)) => start_pipe(driver, &mut pipes, stream, channel, peer, shared, slot),
+                Err(_) => {
+                    let _ = driver.close(&mut channel);
+                }
+            }
+        }
+        progress |= pump_all(driver, &mut pipes, &mut buf);
+        if !progress {
+            idle(driver);
+        }
+    }
+    for pipe in pipes {
+        pipe.close(&driver);
+    }
+    Ok(())
+}
@@ -0,0 +1,435 @@
+use crate::config::{home_dir, match_patterns};
+use crate::error::Error;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use ssh2::{CheckResult, KnownHostFileKind, Session};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// The public key a server presented during the handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostKey {
+    /// The host as written in known_hosts: `host`, or `[host]:port` off port 22.
+    pub host: String,
+    /// Key algorithm, e.g. `ssh-ed25519`.
+    pub key_type: String,
+    /// The public key blob.
+    pub key: Vec<u8>,
+}
+
+impl HostKey {
+    /// Reads the key of a session that has completed its handshake.
+    pub(crate) fn from_session(sess: &Session, host: &str, port: u16) -> Result<Self, Error> {
+        let (key, _) = sess.host_key().ok_or(Error::NotConnected)?;
+        Ok(Self {
+            host: host_pattern(host, port),
+            key_type: key_type(key).unwrap_or_default(),
+            key: key.to_vec(),
+        })
+    }
+
+    /// SHA256 fingerprint in the form OpenSSH prints, e.g. `SHA256:nThbg6kXUpJW...`.
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.key)
+    }
+
+    /// Legacy MD5 fingerprint as OpenSSH prints it, e.g. `MD5:16:27:ac:a5:...`.
+    pub fn fingerprint_md5(&self) -> String {
+        let hex: Vec<String> = Md5::digest(&self.key).iter().map(|b| format!("{:02x}", b)).collect();
+        format!("MD5:{}", hex.join(":"))
+    }
+
+    /// The line recording this key in a known_hosts file, with the host name
+    /// hashed the way OpenSSH's `HashKnownHosts` does if `hashed` is set.
+    fn known_hosts_line(&self, hashed: bool) -> io::Result<String> {
+        let host = if hashed { hash_host(&self.host)? } else { self.host.clone() };
+        Ok(format!("{} {} {}\n", host, self.key_type, STANDARD.encode(&self.key)))
+    }
+}
+
+/// What known_hosts says about a server's key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostKeyStatus {
+    /// The key is recorded for this host.
+    Known,
+    /// Nothing is recorded for this host and key type.
+    Unknown,
+    /// A different key of the same type is recorded for this host.
+    Changed {
+        /// Fingerprint of the recorded key.
+        expected: String,
+    },
+}
+
+/// Decides whether to trust a key, given what known_hosts says about it.
+pub type HostKeyCallback = Box<dyn Fn(&HostKey, &HostKeyStatus) -> bool + Send + Sync>;
+
+/// How to decide whether to trust a server's host key.
+#[derive(Default)]
+pub enum HostKeyPolicy {
+    /// Only keys already in known_hosts are accepted.
+    Strict,
+    /// Unknown hosts are trusted on first use and, if turned on with
+    /// `SSH::set_record_new_host_keys`, recorded in known_hosts; changed keys are
+    /// rejected. Like OpenSSH's `StrictHostKeyChecking accept-new`.
+    #[default]
+    AcceptNew,
+    /// Every key is accepted without looking at known_hosts. Offers no protection
+    /// against man-in-the-middle attacks.
+    AcceptAll,
+    /// The callback decides.
+    Custom(HostKeyCallback),
+}
+
+impl fmt::Debug for HostKeyPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HostKeyPolicy::Strict => write!(f, "Strict"),
+            HostKeyPolicy::AcceptNew => write!(f, "AcceptNew"),
+            HostKeyPolicy::AcceptAll => write!(f, "AcceptAll"),
+            HostKeyPolicy::Custom(_) => write!(f, "Custom(..)"),
+        }
+    }
+}
+
+/// Host key checking settings of an `SSH` object.
+#[derive(Debug, Default)]
+pub(crate) struct HostKeyCheck {
+    pub(crate) policy: HostKeyPolicy,
+    /// `None` means `~/.ssh/known_hosts`.
+    pub(crate) known_hosts: Option<PathBuf>,
+    /// Whether accepted keys of unknown hosts are appended to known_hosts.
+    pub(crate) record_new: bool,
+}
+
+impl HostKeyCheck {
+    /// Checks the key of a freshly handshaken session against the policy.
+    pub(crate) fn verify(&self, sess: &Session, host: &str, port: u16) -> Result<(), Error> {
+        if let HostKeyPolicy::AcceptAll = self.policy {
+            return Ok(());
+        }
+        let key = HostKey::from_session(sess, host, port)?;
+        let path = self.known_hosts.clone().or_else(default_known_hosts);
+        let status = match &path {
+            Some(path) => lookup(sess, path, host, port, &key)?,
+            None => HostKeyStatus::Unknown,
+        };
+        let accepted = match (&self.policy, &status) {
+            (HostKeyPolicy::Custom(decide), status) => decide(&key, status),
+            (_, HostKeyStatus::Known) => true,
+            (HostKeyPolicy::AcceptNew, HostKeyStatus::Unknown) => true,
+            _ => false,
+        };
+        match status {
+            _ if accepted => {}
+            HostKeyStatus::Changed { expected } => {
+                return Err(Error::HostKeyMismatch {
+                    host: key.host.clone(),
+                    expected,
+                    actual: key.fingerprint(),
+                })
+            }
+            _ => return Err(rejected(&key)),
+        }
+        if let (true, HostKeyStatus::Unknown, Some(path)) = (self.record_new, &status, &path) {
+            record(sess, path, host, port, &key)?;
+        }
+        Ok(())
+    }
+}
+
+/// Looks `key` up in a known_hosts file. A missing file knows no hosts.
+///
+/// Entries are checked one at a time so that a recorded key of another type
+/// (say RSA, when the server offered Ed25519) does not count as a mismatch.
+/// libssh2 takes care of hashed host names and the `[host]:port` form.
+///
+/// A key listed under `@revoked` for the host is rejected whatever else the file
+/// says. libssh2 cannot verify host certificates, so a host with a
+/// `@cert-authority` line is rejected unless its plain key is recorded too.
+fn lookup(sess: &Session, path: &Path, host: &str, port: u16, key: &HostKey) -> Result<HostKeyStatus, Error> {
+    match fs::read_to_string(path) {
+        Ok(contents) => check(sess, &contents, host, port, key),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(HostKeyStatus::Unknown),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// What the known_hosts `contents` say about `key`; see `lookup`.
+fn check(sess: &Session, contents: &str, host: &str, port: u16, key: &HostKey) -> Result<HostKeyStatus, Error> {
+    let mut status = HostKeyStatus::Unknown;
+    let mut certified = false;
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(marked) = line.strip_prefix('@') {
+            let (marker, entry) = marked.split_once(char::is_whitespace).unwrap_or((marked, ""));
+            let marked_key = match marked_entry(sess, entry.trim_start(), host, port)? {
+                Some(marked_key) => marked_key,
+                None => continue,
+            };
+            match marker {
+                "revoked" if marked_key == key.key => return Err(rejected(key)),
+                "cert-authority" => certified = true,
+                _ => {}
+            }
+            continue;
+        }
+        let mut entry = sess.known_hosts()?;
+        if entry.read_str(line, KnownHostFileKind::OpenSSH).is_err() {
+            continue;
+        }
+        match entry.check_port(host, port, &key.key) {
+            CheckResult::Match => status = HostKeyStatus::Known,
+            CheckResult::Mismatch if status == HostKeyStatus::Unknown => {
+                let recorded = entry.hosts()?.first().and_then(|h| STANDARD.decode(h.key()).ok());
+                if let Some(recorded) = recorded {
+                    if key_type(&recorded).as_deref() == Some(key.key_type.as_str()) {
+                        status = HostKeyStatus::Changed {
+                            expected: fingerprint(&recorded),
+                        };
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    if certified && status != HostKeyStatus::Known {
+        return Err(rejected(key));
+    }
+    Ok(status)
+}
+
+/// The key of a marked known_hosts entry (`hosts keytype key`, after the marker)
+/// if the entry names `host:port`.
+fn marked_entry(sess: &Session, entry: &str, host: &str, port: u16) -> Result<Option<Vec<u8>>, Error> {
+    let mut fields = entry.split_whitespace();
+    let (hosts, key_type, encoded) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(hosts), Some(key_type), Some(encoded)) => (hosts, key_type, encoded),
+        _ => return Ok(None),
+    };
+    let blob = match STANDARD.decode(encoded) {
+        Ok(blob) => blob,
+        Err(_) => return Ok(None),
+    };
+    let named = if hosts.split(',').any(|h| h.starts_with('|')) {
+        // Hashed names can only be checked by libssh2, against the entry's own key.
+        let mut known = sess.known_hosts()?;
+        let plain = format!("{} {} {}", hosts, key_type, encoded);
+        known.read_str(&plain, KnownHostFileKind::OpenSSH).is_ok()
+            && matches!(known.check_port(host, port, &blob), CheckResult::Match)
+    } else {
+        match_patterns(&host_pattern(host, port), hosts.split(','))
+    };
+    Ok(named.then_some(blob))
+}
+
+/// The error for a key known_hosts forbids.
+fn rejected(key: &HostKey) -> Error {
+    Error::HostKeyRejected {
+        host: key.host.clone(),
+        fingerprint: key.fingerprint(),
+    }
+}
+
+/// Appends a key to a known_hosts file, creating the file if needed. The file is
+/// locked and checked again first, so that connections racing to the same new host
+/// record it once. The entry is hashed if the file's entries are.
+fn record(sess: &Session, path: &Path, host: &str, port: u16, key: &HostKey) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+    lock(&file)?;
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_string(&mut contents)?;
+    if check(sess, &contents, host, port, key)? != HostKeyStatus::Unknown {
+        return Ok(());
+    }
+    file.write_all(key.known_hosts_line(is_hashed(&contents))?.as_bytes())?;
+    Ok(())
+}
+
+/// Takes an exclusive lock on a file, released when it is closed.
+#[cfg(unix)]
+fn lock(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Without `flock`, the check before appending still catches most races.
+#[cfg(not(unix))]
+fn lock(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+/// True if the host names in known_hosts `contents` are hashed, judging by the
+/// first entry.
+fn is_hashed(contents: &str) -> bool {
+    contents
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with('@'))
+        .is_some_and(|line| line.starts_with("|1|"))
+}
+
+/// A host name hashed like OpenSSH does: `|1|salt|HMAC-SHA1(salt, host)`, both in base64.
+fn hash_host(host: &str) -> io::Result<String> {
+    let mut salt = [0; 20];
+    getrandom::getrandom(&mut salt).map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(hashed_name(host, &salt))
+}
+
+fn hashed_name(host: &str, salt: &[u8]) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(salt).expect("HMAC takes keys of any length");
+    mac.update(host.as_bytes());
+    format!("|1|{}|{}", STANDARD.encode(salt), STANDARD.encode(mac.finalize().into_bytes()))
+}
+
+/// `~/.ssh/known_hosts`, if the home directory is known.
+fn default_known_hosts() -> Option<PathBuf> {
+    Some(home_dir()?.join(".ssh").join("known_hosts"))
+}
+
+/// How known_hosts names a host: plain on port 22, `[host]:port` otherwise.
+fn host_pattern(host: &str, port: u16) -> String {
+    if port == 22 {
+        host.to_owned()
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+/// The algorithm name a key blob starts with.
+fn key_type(blob: &[u8]) -> Option<String> {
+    let len = u32::from_be_bytes([*blob.first()?, *blob.get(1)?, *blob.get(2)?, *blob.get(3)?]) as usize;
+    let name = blob.get(4..4usize.checked_add(len)?)?;
+    String::from_utf8(name.to_vec()).ok()
+}
+
+/// SHA256 fingerprint of a key blob.
+fn fingerprint(blob: &[u8]) -> String {
+    format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(blob)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// A key blob of `key_type` whose body is `fill` repeated.
+    fn host_key(host: &str, port: u16, key_type: &str, fill: u8) -> HostKey {
+        let mut key = Vec::new();
+        key.extend_from_slice(&(key_type.len() as u32).to_be_bytes());
+        key.extend_from_slice(key_type.as_bytes());
+        key.extend_from_slice(&32u32.to_be_bytes());
+        key.extend_from_slice(&[fill; 32]);
+        HostKey {
+            host: host_pattern(host, port),
+            key_type: key_type.to_owned(),
+            key,
+        }
+    }
+
+    fn line(hosts: &str, key: &HostKey) -> String {
+        format!("{} {} {}\n", hosts, key.key_type, STANDARD.encode(&key.key))
+    }
+
+    fn status(contents: &str, host: &str, port: u16, key: &HostKey) -> Result<HostKeyStatus, Error> {
+        check(&Session::new().unwrap(), contents, host, port, key)
+    }
+
+    #[test]
+    fn recorded_keys_match_by_host_and_port() {
+        let key = host_key("example.com", 22, "ssh-ed25519", 1);
+        let contents = format!("# comment\n\n{}", line("other.com,example.com", &key));
+        assert_eq!(status(&contents, "example.com", 22, &key).unwrap(), HostKeyStatus::Known);
+        assert_eq!(status(&contents, "nowhere.com", 22, &key).unwrap(), HostKeyStatus::Unknown);
+
+        let off_port = host_key("example.com", 2222, "ssh-ed25519", 1);
+        let contents = line("[example.com]:2222", &off_port);
+        assert_eq!(status(&contents, "example.com", 2222, &off_port).unwrap(), HostKeyStatus::Known);
+        assert_eq!(status(&contents, "example.com", 22, &key).unwrap(), HostKeyStatus::Unknown);
+    }
+
+    #[test]
+    fn only_a_key_of_the_same_type_counts_as_changed() {
+        let key = host_key("example.com", 22, "ssh-ed25519", 1);
+        let recorded = host_key("example.com", 22, "ssh-ed25519", 2);
+        let expected = HostKeyStatus::Changed {
+            expected: recorded.fingerprint(),
+        };
+        assert_eq!(status(&line("example.com", &recorded), "example.com", 22, &key).unwrap(), expected);
+
+        let other_type = host_key("example.com", 22, "ecdsa-sha2-nistp256", 2);
+        let contents = line("example.com", &other_type);
+        assert_eq!(status(&contents, "example.com", 22, &key).unwrap(), HostKeyStatus::Unknown);
+    }
+
+    #[test]
+    fn hashed_names_match() {
+        let key = host_key("example.com", 2222, "ssh-ed25519", 1);
+        let contents = line(&hashed_name("[example.com]:2222", &[7; 20]), &key);
+        assert!(is_hashed(&contents));
+        assert_eq!(status(&contents, "example.com", 2222, &key).unwrap(), HostKeyStatus::Known);
+        assert_eq!(status(&contents, "example.org", 2222, &key).unwrap(), HostKeyStatus::Unknown);
+        assert!(!is_hashed(&line("example.com", &key)));
+    }
+
+    #[test]
+    fn revoked_keys_are_rejected() {
+        let key = host_key("example.com", 22, "ssh-ed25519", 1);
+        let contents = format!("{}@revoked * {} {}\n", line("example.com", &key), key.key_type, STANDARD.encode(&key.key));
+        assert!(matches!(status(&contents, "example.com", 22, &key), Err(Error::HostKeyRejected { .. })));
+
+        let other = host_key("example.com", 22, "ssh-ed25519", 2);
+        let contents = format!("{}@revoked example.com {} {}\n", line("example.com", &key), other.key_type, STANDARD.encode(&other.key));
+        assert_eq!(status(&contents, "example.com", 22, &key).unwrap(), HostKeyStatus::Known);
+    }
+
+    #[test]
+    fn cert_authority_needs_a_plain_key() {
+        let key = host_key("db.example.com", 22, "ssh-ed25519", 1);
+        let ca = host_key("ca", 22, "ssh-ed25519", 9);
+        let marker = format!("@cert-authority *.example.com {} {}\n", ca.key_type, STANDARD.encode(&ca.key));
+        assert!(matches!(status(&marker, "db.example.com", 22, &key), Err(Error::HostKeyRejected { .. })));
+        assert_eq!(status(&marker, "web.example.org", 22, &key).unwrap(), HostKeyStatus::Unknown);
+
+        let contents = format!("{}{}", marker, line("db.example.com", &key));
+        assert_eq!(status(&contents, "db.example.com", 22, &key).unwrap(), HostKeyStatus::Known);
+    }
+
+    #[test]
+    fn recording_keeps_the_file_style_and_writes_once() {
+        let dir = env::temp_dir().join(format!("sshrs-hostkey-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let sess = Session::new().unwrap();
+        let key = host_key("example.com", 2222, "ssh-ed25519", 1);
+
+        let plain = dir.join("plain");
+        record(&sess, &plain, "example.com", 2222, &key).unwrap();
+        record(&sess, &plain, "example.com", 2222, &key).unwrap();
+        assert_eq!(fs::read_to_string(&plain).unwrap(), line("[example.com]:2222", &key));
+
+        let hashed = dir.join("hashed");
+        let other = host_key("other.com", 22, "ssh-ed25519", 2);
+        fs::write(&hashed, line(&hashed_name("other.com", &[3; 20]), &other)).unwrap();
+        record(&sess, &hashed, "example.com", 2222, &key).unwrap();
+        let contents = fs::read_to_string(&hashed).unwrap();
+        assert_eq!(contents.lines().filter(|line| line.starts_with("|1|")).count(), 2);
+        assert_eq!(check(&sess, &contents, "example.com", 2222, &key).unwrap(), HostKeyStatus::Known);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,102 @@
+use crate::driver::{Driver, Link};
+use crate::error::Error;
+use crate::forward::Shared;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the keepalive thread checks whether it should stop.
+const TICK: Duration = Duration::from_millis(100);
+
+/// libssh2 turns shorter keepalive intervals into this one.
+pub(crate) const MIN_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Keepalives sent in the background, like OpenSSH's `ServerAliveInterval`
+/// and `ServerAliveCountMax`.
+///
+/// Every `interval` the server is sent a keepalive that asks for a reply. Once
+/// `count_max` keepalives in a row go by without the server sending anything,
+/// the connection is cut: calls waiting on it fail, forwards stop, and the next
+/// operation reconnects if a reconnect policy is set.
+///
+/// libssh2 swallows the server's replies, so silence is measured by the kernel,
+/// which only Linux reports. On other platforms (macOS, the BSDs, Windows)
+/// `count_max` has no effect: keepalives still keep idle connections open and a
+/// connection the OS closes is noticed, but a peer that silently went away is
+/// not. `counts_unanswered` tells which applies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepalivePolicy {
+    pub(crate) interval: Duration,
+    pub(crate) count_max: u32,
+}
+
+impl KeepalivePolicy {
+    /// A keepalive every `interval` (at least two seconds), giving up after three
+    /// unanswered ones.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval: interval.max(MIN_INTERVAL),
+            count_max: 3,
+        }
+    }
+
+    /// How many keepalives in a row may go unanswered. Zero never gives up.
+    /// Ignored where `counts_unanswered` is false.
+    pub fn count_max(mut self, count: u32) -> Self {
+        self.count_max = count;
+        self
+    }
+
+    /// Whether unanswered keepalives are counted on this platform, so that
+    /// `count_max` can cut a connection to a peer that went away.
+    pub fn counts_unanswered() -> bool {
+        cfg!(target_os = "linux")
+    }
+}
+
+/// Sends keepalives over the link's current session until stopped, following it
+/// across reconnects.
+pub(crate) fn run(link: &Link, policy: KeepalivePolicy, shared: &Shared) -> Result<(), Error> {
+    let mut probed: Option<Driver> = None;
+    let mut sent = Instant::now();
+    let mut missed = 0;
+    while !shared.stopped() {
+        thread::sleep(TICK);
+        let since_probe = sent.elapsed();
+        if since_probe < policy.interval {
+            continue;
+        }
+        sent = Instant::now();
+        let driver = match link.get().filter(Driver::is_alive) {
+            Some(driver) => driver,
+            None => continue,
+        };
+        missed = match &probed {
+            Some(previous) if previous.same_session(&driver) && !answered(&driver, since_probe) => missed + 1,
+            _ => 0,
+        };
+        if KeepalivePolicy::counts_unanswered() && policy.count_max > 0 && missed >= policy.count_max {
+            driver.mark_dead();
+            probed = None;
+            continue;
+        }
+        let _ = probe(&driver, policy.interval);
+        probed = Some(driver);
+    }
+    Ok(())
+}
+
+/// Whether the server has sent anything since a probe sent `since_probe` ago.
+/// Always true where the kernel does not say.
+pub(crate) fn answered(driver: &Driver, since_probe: Duration) -> bool {
+    // The kernel's clock is coarse, so replies that come straight away need some slack.
+    driver.silent_for().is_none_or(|silent| silent < since_probe + TICK)
+}
+
+/// Sends a keepalive that asks for a reply. `interval` must match how often probes
+/// are sent, at least `MIN_INTERVAL`.
+pub(crate) fn probe(driver: &Driver, interval: Duration) -> Result<(), ssh2::Error> {
+    // libssh2 skips keepalives sent sooner than its own interval allows.
+    driver.sess.set_keepalive(true, interval.as_secs() as u32);
+    let probe = driver.for_operation(Some(interval));
+    probe.retry(|| probe.sess.keepalive_send()).map(|_| ())
+}
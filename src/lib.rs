@@ -1,61 +1,395 @@
-use failure::Error;
 use std::collections::HashMap;
-use ssh2::{Session, ScpFileStat, Channel};
-use std::fs::File;
-use std::io::prelude::*;
-use std::io::BufReader;
-use std::net::TcpStream;
-use std::path::Path;
+use std::io::Write;
+use ssh2::{Session, ScpFileStat};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
 use std::str;
 use std::string::String;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
 
-const SCPMODE: i32 = 0o644; // chmod 644
+#[cfg(all(feature = "async", unix))]
+mod async_driver;
+#[cfg(all(feature = "async", unix))]
+mod async_forward;
+#[cfg(all(feature = "async", unix))]
+mod async_sftp;
+#[cfg(all(feature = "async", unix))]
+mod async_ssh;
+mod auth;
+mod config;
+mod driver;
+mod error;
+mod exec;
+mod forward;
+mod hostkey;
+mod keepalive;
+mod mux;
+mod options;
+mod pool;
+mod prompt;
+mod proxy;
+mod reconnect;
+mod scp;
+mod sftp;
+#[cfg(unix)]
+mod shell;
+mod socks;
+mod tcp;
+
+use crate::driver::{Driver, Link, Transport};
+use crate::forward::Worker;
+use crate::mux::ChannelSlot;
+use crate::reconnect::{is_permanent, Login};
+#[cfg(all(feature = "async", unix))]
+pub use crate::async_forward::{AsyncLocalForward, AsyncRemoteForward, AsyncTunnel};
+#[cfg(all(feature = "async", unix))]
+pub use crate::async_sftp::AsyncSftp;
+#[cfg(all(feature = "async", unix))]
+pub use crate::async_ssh::{AsyncRemoteProcess, AsyncSSH};
+pub use crate::auth::AuthChain;
+pub use crate::config::HostConfig;
+pub use crate::error::Error;
+pub use crate::exec::{Chunk, CommandOutput, RemoteProcess, StreamKind};
+pub use crate::forward::{ConnectionStats, LocalForward, RemoteForward, Tunnel};
+use crate::hostkey::HostKeyCheck;
+pub use crate::hostkey::{HostKey, HostKeyCallback, HostKeyPolicy, HostKeyStatus};
+pub use crate::keepalive::KeepalivePolicy;
+pub use crate::mux::SharedSSH;
+pub use crate::options::{AddressFamily, ConnectOptions};
+pub use crate::pool::{Credentials, PoolOptions, PoolSetup, PooledSSH, SshPool};
+#[cfg(unix)]
+pub use crate::prompt::TtyPrompter;
+pub use crate::prompt::{Prompt, Prompter, StaticPrompter};
+pub use crate::reconnect::{ReconnectCallback, ReconnectEvent, ReconnectPolicy};
+pub use crate::sftp::{FileStat, OpenFlags, Sftp, SftpFile};
+#[cfg(unix)]
+pub use crate::shell::InteractiveShell;
+pub use crate::socks::SocksProxy;
+
+/// How long a failed handshake waits for a dying relay to report why.
+const RELAY_GRACE: Duration = Duration::from_millis(200);
 
 pub struct SSH {
-    session: Option<Session>,
+    /// Shared with forwards, which follow it across reconnects.
+    session: Arc<Link>,
+    /// Replayed when reconnecting. Also serializes reconnects.
+    login: Mutex<Option<Login>>,
+    reconnect: Option<ReconnectPolicy>,
+    on_reconnect: Option<ReconnectCallback>,
+    /// The session connections are carried through, for hosts behind a jump host.
+    jump: Option<Driver>,
     host: String,
     port: u16,
+    host_keys: HostKeyCheck,
+    user: Option<String>,
+    identity_files: Vec<PathBuf>,
+    proxy_jump: Option<String>,
+    proxy_command: Option<String>,
+    keepalive: Option<KeepalivePolicy>,
+    /// Sends keepalives once connected, if a keepalive policy is set.
+    prober: Mutex<Option<Worker>>,
+    options: ConnectOptions,
 }
 
 impl SSH {
 
     /// Creates an SSH object with the host/IP and port. The connection is not established at this point.
+    /// IPv6 addresses may be written with or without brackets.
     pub fn new(host: &str, port: u16) -> Self {
         Self {
-            session: None,
-            host: host.to_owned(),
-            port: port,
+            session: Arc::default(),
+            login: Mutex::new(None),
+            reconnect: None,
+            on_reconnect: None,
+            jump: None,
+            host: tcp::unbracket(host).to_owned(),
+            port,
+            host_keys: HostKeyCheck::default(),
+            user: None,
+            identity_files: Vec::new(),
+            proxy_jump: None,
+            proxy_command: None,
+            keepalive: None,
+            prober: Mutex::new(None),
+            options: ConnectOptions::default(),
+        }
+    }
+
+    /// Creates an SSH object for `target_host:port` as seen from `jump`, like `ssh -J`.
+    /// The connection is carried over a `direct-tcpip` channel of the jump session, so
+    /// chains of any length are built by passing an SSH object created this way.
+    /// Log in with any of the connect methods; `jump` may be dropped afterwards.
+    pub fn connect_via(jump: &SSH, target_host: &str, port: u16) -> Result<Self, Error> {
+        let mut ssh = Self::new(target_host, port);
+        ssh.jump = Some(jump.sess_ref()?);
+        Ok(ssh)
+    }
+
+    /// Creates an SSH object for a host alias from `~/.ssh/config` and `/etc/ssh/ssh_config`.
+    /// Use `connect_configured` to log in as the configured user.
+    pub fn from_config(alias: &str) -> Result<Self, Error> {
+        Ok(Self::from_host_config(alias, &HostConfig::load(alias)?))
+    }
+
+    /// Creates an SSH object from already resolved configuration. HostName, Port, User,
+    /// IdentityFile, ProxyJump, ProxyCommand, ServerAliveInterval, ServerAliveCountMax, ConnectTimeout, AddressFamily,
+    /// StrictHostKeyChecking and UserKnownHostsFile are applied.
+    pub fn from_host_config(alias: &str, config: &HostConfig) -> Self {
+        let mut ssh = Self::new(config.host_name.as_deref().unwrap_or(alias), config.port.unwrap_or(22));
+        ssh.user = config.user.clone();
+        ssh.identity_files = config.identity_files.clone();
+        ssh.proxy_jump = config.proxy_jump.clone();
+        ssh.proxy_command = config.proxy_command.clone();
+        if let Some(interval) = config.server_alive_interval.filter(|&i| i > 0) {
+            let policy = KeepalivePolicy::new(Duration::from_secs(interval.into()));
+            ssh.keepalive = Some(config.server_alive_count_max.map_or(policy, |count| policy.count_max(count)));
+        }
+        match config.address_family.as_deref() {
+            Some("inet") => ssh.options = ssh.options.address_family(AddressFamily::Ipv4Only),
+            Some("inet6") => ssh.options = ssh.options.address_family(AddressFamily::Ipv6Only),
+            _ => {}
+        }
+        if let Some(timeout) = config.connect_timeout {
+            ssh.options = ssh.options.connect_timeout(timeout).handshake_timeout(timeout);
+        }
+        match config.strict_host_key_checking.as_deref() {
+            // Nobody to ask, so "ask" behaves like "yes".
+            Some("yes") | Some("ask") => ssh.set_host_key_policy(HostKeyPolicy::Strict),
+            Some("accept-new") => {
+                ssh.set_host_key_policy(HostKeyPolicy::AcceptNew);
+                ssh.set_record_new_host_keys(true);
+            }
+            Some("no") | Some("off") => ssh.set_host_key_policy(HostKeyPolicy::AcceptAll),
+            _ => {}
+        }
+        if let Some(path) = &config.user_known_hosts_file {
+            ssh.set_known_hosts_file(path);
+        }
+        ssh
+    }
+
+    /// Sets the connect, handshake and operation timeouts. Takes effect for the
+    /// current session too, except for objects already opened on it.
+    pub fn set_connect_options(&mut self, options: ConnectOptions) {
+        self.options = options;
+        if let Some(mut driver) = self.session.get() {
+            driver.set_timeout(options.operation_timeout);
+            self.session.set(Some(driver));
+        }
+    }
+
+    /// Re-establishes the session when an operation finds the connection gone.
+    /// Reconnecting is off until a policy is set. Logins made with
+    /// `connect_keyboard_interactive` cannot be replayed; use an `AuthChain` instead.
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        self.reconnect = Some(policy);
+    }
+
+    /// Sends keepalives in the background and, on Linux, cuts the connection once the
+    /// server stops answering them (see `KeepalivePolicy`). Takes effect for the
+    /// current session too.
+    pub fn set_keepalive_policy(&mut self, policy: KeepalivePolicy) {
+        self.keepalive = Some(policy);
+        *self.prober.get_mut().unwrap_or_else(PoisonError::into_inner) = None;
+        if self.session.get().is_some() {
+            self.start_keepalive();
+        }
+    }
+
+    /// Limits how many channels may be open on the session at once: commands,
+    /// transfers, SFTP sessions, tunnels, shells and forwarded connections. Callers
+    /// wait for a free channel, up to the operation timeout; forwards leave new
+    /// connections waiting in the listen backlog. OpenSSH servers refuse more than 10
+    /// sessions per connection by default. `None`, the default, sets no limit.
+    pub fn set_max_channels(&mut self, max: Option<usize>) {
+        self.session.channels.set_max(max);
+    }
+
+    /// Calls `callback` as reconnecting progresses.
+    pub fn on_reconnect<F>(&mut self, callback: F)
+    where
+        F: Fn(&ReconnectEvent) + Send + Sync + 'static,
+    {
+        self.on_reconnect = Some(Box::new(callback));
+    }
+
+    /// Connects through a command's stdin and stdout instead of a TCP socket, like
+    /// OpenSSH's ProxyCommand (e.g. `nc -X connect -x proxy:3128 %h %p`). The command
+    /// runs through the shell; `%h`, `%p`, `%r` and `%%` expand to the host, port,
+    /// user and a literal `%`.
+    pub fn set_proxy_command(&mut self, command: &str) {
+        self.proxy_command = Some(command.to_owned());
+    }
+
+    /// Sets how the server's host key is checked when connecting.
+    /// The default, `AcceptNew`, trusts unknown hosts but rejects changed keys.
+    pub fn set_host_key_policy(&mut self, policy: HostKeyPolicy) {
+        self.host_keys.policy = policy;
+    }
+
+    /// Uses `path` instead of `~/.ssh/known_hosts` for host key checks.
+    pub fn set_known_hosts_file(&mut self, path: &Path) {
+        self.host_keys.known_hosts = Some(path.to_owned());
+    }
+
+    /// Appends the keys of unknown hosts to known_hosts once the policy accepts them.
+    /// Off by default.
+    pub fn set_record_new_host_keys(&mut self, record: bool) {
+        self.host_keys.record_new = record;
+    }
+
+    /// Waits for the channel limit to allow another channel, as long as a call on
+    /// `driver` may wait.
+    fn channel_slot(&self, driver: &Driver) -> Result<ChannelSlot, Error> {
+        self.session.channels.acquire(driver.limit())
+    }
+
+    /// Returns the current session, reconnecting first if it was lost and a
+    /// reconnect policy is set.
+    fn sess_ref(&self) -> Result<Driver, Error> {
+        let driver = self.session.get().ok_or(Error::NotConnected)?;
+        if driver.is_alive() {
+            return Ok(driver);
+        }
+        match &self.reconnect {
+            Some(policy) => self.reconnect(policy),
+            None => Err(Error::ConnectionLost),
+        }
+    }
+
+    /// Logs in again with the remembered credentials, following `policy`.
+    fn reconnect(&self, policy: &ReconnectPolicy) -> Result<Driver, Error> {
+        let mut login = self.login.lock().unwrap_or_else(PoisonError::into_inner);
+        // Another thread may have reconnected while we waited for the lock.
+        if let Some(driver) = self.session.get().filter(Driver::is_alive) {
+            return Ok(driver);
+        }
+        let login = login.as_mut().ok_or(Error::ConnectionLost)?;
+        self.emit(ReconnectEvent::Disconnected);
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let delay = policy.delay(attempt);
+            self.emit(ReconnectEvent::Attempt { attempt, delay });
+            thread::sleep(delay);
+            let res = self.create_socket(&login.username).and_then(|(sess, transport)| {
+                login.chain.authenticate(&sess, &login.username)?;
+                Ok(self.establish(sess, transport))
+            });
+            match res {
+                Ok(driver) => {
+                    self.emit(ReconnectEvent::Reconnected { attempts: attempt });
+                    return Ok(driver);
+                }
+                Err(error) => {
+                    self.emit(ReconnectEvent::AttemptFailed { attempt, error: &error });
+                    if is_permanent(&error) || attempt >= policy.max_attempts {
+                        self.emit(ReconnectEvent::GaveUp { attempts: attempt });
+                        return Err(error);
+                    }
+                }
+            }
+        }
+    }
+
+    fn emit(&self, event: ReconnectEvent) {
+        if let Some(callback) = &self.on_reconnect {
+            callback(&event);
         }
     }
 
-    /// Returns a reference to self.session. This is to clean up code in other functions.
-    fn sess_ref(&self) -> &Session {
-        self.session.as_ref().unwrap()
+    /// Connect to the server (directly, through jump hosts or through a proxy command),
+    /// establish handshake and check its host key.
+    fn create_socket(&self, username: &str) -> Result<(Session, Transport), Error> {
+        let jump = match (&self.jump, &self.proxy_jump) {
+            (Some(jump), _) => Some(jump.clone()),
+            (None, Some(hops)) => Some(Self::jump_chain(hops)?),
+            (None, None) => None,
+        };
+        let (socket, relay) = match (jump, &self.proxy_command) {
+            (Some(jump), _) => {
+                let (socket, relay) = forward::relay(jump, &self.host, self.port)?;
+                (socket, Some(relay))
+            }
+            (None, Some(command)) => {
+                let port = self.port.to_string();
+                let command = config::expand_tokens(command, &[('h', &self.host), ('p', &port), ('r', username)]);
+                let (socket, relay) = proxy::spawn(&command)?;
+                (socket, Some(relay))
+            }
+            (None, None) => (tcp::connect(&self.host, self.port, &self.options)?, None),
+        };
+        let (sess, socket) = match Self::handshake(socket, self.options.handshake_timeout) {
+            Ok(res) => res,
+            // A relay that died, such as a proxy command that could not reach the
+            // host, explains the failure better than libssh2 can.
+            Err(e) => return Err(relay.and_then(|relay| relay.failure(RELAY_GRACE)).unwrap_or(e)),
+        };
+        self.host_keys.verify(&sess, &self.host, self.port)?;
+        // Authentication runs in blocking mode, bounded by libssh2 itself.
+        sess.set_timeout(self.options.operation_timeout.map_or(0, |t| t.as_millis() as u32));
+        Ok((sess, Transport::new(socket, relay)))
+    }
+
+    /// Logs in to each hop of a ProxyJump list (`[user@]host[:port],...`) in turn,
+    /// each through the previous one, and returns the session of the last.
+    /// Hops are resolved from the config files, ignoring their own ProxyJump.
+    fn jump_chain(hops: &str) -> Result<Driver, Error> {
+        let mut jump: Option<Driver> = None;
+        for hop in hops.split(',') {
+            let (user, alias, port) = config::parse_jump(hop)?;
+            let mut ssh = Self::from_config(alias)?;
+            ssh.proxy_jump = None;
+            ssh.jump = jump.take();
+            if let Some(user) = user {
+                ssh.user = Some(user.to_owned());
+            }
+            if let Some(port) = port {
+                ssh.port = port;
+            }
+            ssh.connect_configured()?;
+            jump = ssh.session.get();
+        }
+        jump.ok_or_else(|| Error::Config("empty ProxyJump".to_owned()))
     }
 
-    /// Create a TCP socket and establish handshake with server.
-    fn create_socket(&self) -> Result<Session, Error> {
-        let socket = TcpStream::connect(format!("{}:{}", self.host, self.port))?;
+    /// Runs the SSH handshake over `socket`, without authenticating. Also returns a
+    /// second handle on the socket for waiting on readiness.
+    fn handshake(socket: TcpStream, timeout: Option<Duration>) -> Result<(Session, TcpStream), Error> {
+        let waiter = socket.try_clone().map_err(Error::Connect)?;
         let mut sess = Session::new()?;
         sess.set_tcp_stream(socket);
+        if let Some(timeout) = timeout {
+            sess.set_timeout(timeout.as_millis() as u32);
+        }
+        sess.handshake().map_err(Error::handshake)?;
+        sess.set_timeout(0);
+        Ok((sess, waiter))
+    }
 
-        // Handshake and authentication
-        sess.handshake()?;
-        Ok(sess)
+    /// Fetches the host key a server presents, without authenticating or
+    /// consulting known_hosts. Useful for recording fingerprints.
+    pub fn probe_host_key(host: &str, port: u16) -> Result<HostKey, Error> {
+        let host = tcp::unbracket(host);
+        let (sess, _) = Self::handshake(tcp::connect(host, port, &ConnectOptions::default())?, None)?;
+        let key = HostKey::from_session(&sess, host, port)?;
+        let _ = sess.disconnect(None, "host key probe", None);
+        Ok(key)
     }
 
     /// Returns list of identities known in `ssh-agent`
     pub fn identities() -> Result<HashMap<String, Vec<u8>>, Error> {
-        let sess = Session::new().unwrap();
-        let mut agent = sess.agent().unwrap();
+        let sess = Session::new()?;
+        let mut agent = sess.agent()?;
 
         // Connect the agent and request a list of identities
-        agent.connect().unwrap();
-        agent.list_identities().unwrap();
+        agent.connect()?;
+        agent.list_identities()?;
 
         let mut identities = HashMap::new();
-        for identity in agent.identities() {
-            let identity = identity.unwrap(); // assume no I/O errors
+        for identity in agent.identities()? {
             identities.insert(
                 identity.comment().to_owned(),
                 identity.blob().to_owned());
@@ -65,95 +399,339 @@ impl SSH {
 
     /// Initialize connection and authenticate to SSH server
     pub fn connect(&mut self, username: &str, pass: &str) -> Result<(), Error> {
-        let sess = self.create_socket()?;
-        sess.userauth_password(username, pass)?;
-        assert!(sess.authenticated()); // Convert this to `Error` type
-        self.session = Some(sess);
+        let (sess, transport) = self.create_socket(username)?;
+        Self::check_auth(&sess, "password", sess.userauth_password(username, pass))?;
+        self.establish(sess, transport);
+        self.remember(username, Some(AuthChain::new().password(pass)));
         Ok(())
     }
 
     /// Authenticate using `ssh-agent`.
     /// This allows for use of public key instead of username and password.
     pub fn connect_agent(&mut self, username:&str) -> Result<(), Error> {
-        let sess = self.create_socket()?;
-        sess.userauth_agent(username)?;
-        assert!(sess.authenticated()); // Convert this to `Error` type
-        self.session = Some(sess);
+        let (sess, transport) = self.create_socket(username)?;
+        Self::check_auth(&sess, "publickey", sess.userauth_agent(username))?;
+        self.establish(sess, transport);
+        self.remember(username, Some(AuthChain::new().agent()));
+        Ok(())
+    }
+
+    /// Authenticate with a private key file, e.g. a deploy key.
+    /// The public key is derived from the private key when `public_key` is `None`.
+    pub fn connect_with_key(&mut self, username: &str, private_key: &Path, public_key: Option<&Path>, passphrase: Option<&str>) -> Result<(), Error> {
+        let (sess, transport) = self.create_socket(username)?;
+        let res = sess.userauth_pubkey_file(username, public_key, private_key, passphrase);
+        Self::check_auth(&sess, "publickey", res)?;
+        self.establish(sess, transport);
+        self.remember(username, Some(AuthChain::new().key_file(private_key, public_key, passphrase)));
+        Ok(())
+    }
+
+    /// Authenticate with a PEM or OpenSSH private key held in memory, e.g. read from
+    /// an environment variable. The public key is derived when `public_key` is `None`.
+    #[cfg(unix)]
+    pub fn connect_with_key_data(&mut self, username: &str, private_key: &str, public_key: Option<&str>, passphrase: Option<&str>) -> Result<(), Error> {
+        let (sess, transport) = self.create_socket(username)?;
+        let res = sess.userauth_pubkey_memory(username, public_key, private_key, passphrase);
+        Self::check_auth(&sess, "publickey", res)?;
+        self.establish(sess, transport);
+        self.remember(username, Some(AuthChain::new().key_data(private_key, public_key, passphrase)));
+        Ok(())
+    }
+
+    /// Authenticate with keyboard-interactive, as used for PAM and one-time codes.
+    /// Every round of server prompts is passed to `prompter` to answer.
+    pub fn connect_keyboard_interactive<P: Prompter>(&mut self, username: &str, prompter: &mut P) -> Result<(), Error> {
+        let (sess, transport) = self.create_socket(username)?;
+        let res = sess.userauth_keyboard_interactive(username, &mut prompt::Adapter(prompter));
+        Self::check_auth(&sess, "keyboard-interactive", res)?;
+        self.establish(sess, transport);
+        // The prompter is only borrowed, so this login cannot be replayed.
+        self.remember(username, None);
         Ok(())
     }
 
+    /// Authenticate by falling back through `chain`, trying only the methods the server offers.
+    /// On failure, `Error::AuthFailed` lists every method that was attempted.
+    pub fn connect_with_chain(&mut self, username: &str, mut chain: AuthChain) -> Result<(), Error> {
+        let (sess, transport) = self.create_socket(username)?;
+        chain.authenticate(&sess, username)?;
+        self.establish(sess, transport);
+        self.remember(username, Some(chain));
+        Ok(())
+    }
+
+    /// Logs in as the configured user (or the local one) with `ssh-agent`, then the
+    /// configured identity files, or the default `~/.ssh/id_*` keys if there are none.
+    pub fn connect_configured(&mut self) -> Result<(), Error> {
+        let user = self.user.clone().or_else(config::local_user)
+            .ok_or_else(|| Error::Config("no user configured".to_owned()))?;
+        let mut keys = self.identity_files.clone();
+        if keys.is_empty() {
+            if let Some(home) = config::home_dir() {
+                let ssh_dir = home.join(".ssh");
+                keys = ["id_ed25519", "id_ecdsa", "id_rsa"].iter().map(|name| ssh_dir.join(name)).collect();
+            }
+        }
+        let mut chain = AuthChain::new().agent();
+        for key in keys.iter().filter(|key| key.exists()) {
+            chain = chain.key_file(key, None, None);
+        }
+        self.connect_with_chain(&user, chain)
+    }
+
+    /// Takes over an authenticated session, making it the current one.
+    fn establish(&self, sess: Session, transport: Transport) -> Driver {
+        let mut driver = Driver::new(sess, transport);
+        driver.set_timeout(self.options.operation_timeout);
+        self.session.set(Some(driver.clone()));
+        self.start_keepalive();
+        driver
+    }
+
+    /// Starts sending keepalives unless already doing so. The keepalive thread
+    /// follows the session across reconnects.
+    fn start_keepalive(&self) {
+        let policy = match self.keepalive {
+            Some(policy) => policy,
+            None => return,
+        };
+        let mut prober = self.prober.lock().unwrap_or_else(PoisonError::into_inner);
+        if prober.is_none() {
+            let link = Arc::clone(&self.session);
+            *prober = Some(Worker::spawn(move |shared| keepalive::run(&link, policy, shared)));
+        }
+    }
+
+    /// Keeps a successful login for reconnecting.
+    fn remember(&mut self, username: &str, chain: Option<AuthChain>) {
+        let login = chain.map(|chain| Login {
+            username: username.to_owned(),
+            chain,
+        });
+        *self.login.get_mut().unwrap_or_else(PoisonError::into_inner) = login;
+    }
+
+    /// Checks the outcome of a single authentication attempt. The server must now
+    /// consider the session authenticated; see `Error::auth` for how failures map.
+    fn check_auth(sess: &Session, method: &str, res: Result<(), ssh2::Error>) -> Result<(), Error> {
+        match res {
+            Ok(()) if sess.authenticated() => Ok(()),
+            // A partial success: the server wants another method as well.
+            Ok(()) => Err(Error::AuthFailed { methods_tried: vec![method.to_owned()] }),
+            Err(err) => Err(Error::auth(method, err)),
+        }
+    }
+
     /// Returns a bool based on status of authentication.
     pub fn authed(&self) -> bool {
-        self.sess_ref().authenticated()
+        self.session.get().is_some_and(|d| d.is_alive() && d.sess.authenticated())
     }
 
     /// Keepalive settings.
     /// Reply determines if we want a response from server
     /// Interval is the number of seconds
-    pub fn keepalive(&mut self, reply: bool, interval: u32) -> Result<(), Error> {
-        self.sess_ref().set_keepalive(reply, interval);
-        self.sess_ref().keepalive_send()?;
+    /// Sends a single keepalive; see `set_keepalive_policy` for sending them in the background.
+    pub fn keepalive(&self, reply: bool, interval: u32) -> Result<(), Error> {
+        let driver = self.sess_ref()?;
+        driver.sess.set_keepalive(reply, interval);
+        driver.retry(|| driver.sess.keepalive_send())?;
         Ok(())
     }
 
-    /// An SSH tunnel. Unfortunately this is not functional as of right now.
-    pub fn tunnel(&mut self, host: &str, port: u16, dst: Option<(&str, u16)>) -> Result<Channel, Error> {
-        assert_eq!(self.authed(), true);
-        let sess = self.sess_ref();
-        let channel = sess.channel_direct_tcpip(host, port, dst).unwrap();
-        Ok(channel)
+    /// Opens a connection to `host:port` as seen from the server, carried over the session.
+    /// `src` is the originating address reported to the server.
+    pub fn tunnel(&self, host: &str, port: u16, src: Option<(&str, u16)>) -> Result<Tunnel, Error> {
+        let driver = self.sess_ref()?;
+        Tunnel::open(&driver, host, port, src, self.channel_slot(&driver)?)
     }
 
 
-    /// SSH forwarding. Not functional.
-    pub fn forward(&mut self, host: &str, port: u16, dst: Option<(&str, u16)>) -> Result<Channel, Error> {
-        assert_eq!(self.authed(), true);
-        let sess = self.sess_ref();
-        let channel = sess.channel_direct_tcpip(host, port, dst).unwrap();
-        Ok(channel)
+    /// Local port forwarding, like `ssh -L`. Connections accepted on `bind_addr` are
+    /// forwarded to `host:port` as seen from the server until the returned handle is stopped.
+    pub fn local_forward<A: ToSocketAddrs>(&self, bind_addr: A, host: &str, port: u16) -> Result<LocalForward, Error> {
+        LocalForward::start(Arc::clone(&self.session), self.sess_ref()?, bind_addr, host, port)
     }
 
-    /// Still a work in progress for interactive shell.
-    pub fn get_shell(&self) -> Result<(), Error> {
-        let mut channel = self.sess_ref().channel_session()?;
-        channel.request_pty("xterm", None, None)?;
-        channel.shell()?;
-        channel.close()?;
-        Ok(())
+    /// Remote port forwarding, like `ssh -R`. The server listens on `bind_host:remote_port`
+    /// (all interfaces when `bind_host` is `None`, a free port when `remote_port` is 0) and
+    /// each connection it accepts is forwarded to `target` until the returned handle is stopped.
+    pub fn remote_forward<A: ToSocketAddrs>(&self, bind_host: Option<&str>, remote_port: u16, target: A) -> Result<RemoteForward, Error> {
+        RemoteForward::start(Arc::clone(&self.session), self.sess_ref()?, bind_host, remote_port, target)
+    }
+
+    /// Dynamic port forwarding, like `ssh -D`. Runs a SOCKS4a/SOCKS5 proxy on `bind_addr`
+    /// whose connections are carried over the session until the returned handle is stopped.
+    pub fn socks_proxy<A: ToSocketAddrs>(&self, bind_addr: A) -> Result<SocksProxy, Error> {
+        SocksProxy::start(Arc::clone(&self.session), self.sess_ref()?, bind_addr)
     }
 
-    /// Run a command on the server.
-    pub fn run_command(&self, cmd: &str) -> Result<String, Error> { 
-        // Would be interesting to use fn(Channel)->String here
-        let mut channel = self.sess_ref().channel_session()?;
-        channel.exec(cmd)?;
-        let mut stdout = String::new();
-        channel.read_to_string(&mut stdout)?;
-        Ok(stdout)
+    /// Opens an interactive login shell on a PTY. Call `run` on the result to hand
+    /// the local terminal over to it.
+    #[cfg(unix)]
+    pub fn get_shell(&self) -> Result<InteractiveShell, Error> {
+        let driver = self.sess_ref()?;
+        InteractiveShell::open(&driver, self.channel_slot(&driver)?)
     }
 
-    /// SCP a file to the server.
+    /// Run a command on the server and return its stdout.
+    /// Use `exec` to also get stderr and the exit status.
+    pub fn run_command(&self, cmd: &str) -> Result<String, Error> {
+        self.operation(None).run_command(cmd)
+    }
+
+    /// Run a command on the server, collecting stdout, stderr and how it exited.
+    /// A non-zero exit is not an error; call `check()` on the result for that.
+    pub fn exec(&self, cmd: &str) -> Result<CommandOutput, Error> {
+        self.operation(None).exec(cmd)
+    }
+
+    /// Overrides the operation timeout for a single command or transfer, e.g.
+    /// `ssh.with_timeout(Duration::from_secs(5)).run_command("uptime")`.
+    pub fn with_timeout(&self, timeout: Duration) -> WithTimeout<'_> {
+        self.operation(Some(timeout))
+    }
+
+    fn operation(&self, timeout: Option<Duration>) -> WithTimeout<'_> {
+        WithTimeout { ssh: self, timeout }
+    }
+
+    /// Start a command on the server without waiting for it, to stream its output
+    /// as it arrives and feed its stdin.
+    pub fn exec_stream(&self, cmd: &str) -> Result<RemoteProcess, Error> {
+        let driver = self.sess_ref()?;
+        RemoteProcess::start(&driver, cmd, self.channel_slot(&driver)?)
+    }
+
+    /// Starts an SFTP session for file management and random-access transfers.
+    pub fn sftp(&self) -> Result<Sftp, Error> {
+        let driver = self.sess_ref()?;
+        Sftp::start(&driver, self.channel_slot(&driver)?)
+    }
+
+    /// SCP a file to the server, keeping its permissions and modification time.
     pub fn upload_file(&self, fpath: &Path, dest: &Path) -> Result<(), Error> {
-        let sess = self.sess_ref();
-        // Read file to SCP into u8 array and get it's length
-        let file = File::open(fpath)?;
-        let mut reader = BufReader::new(file);
-        let data = reader.fill_buf()?;
-        let data_len = data.len() as u64;
-
-        // Transfer file
-        sess.scp_send(dest, SCPMODE, data_len, None)
-            .unwrap()
-            .write(data)?;
-        Ok(())
+        self.operation(None).upload_file(fpath, dest)
+    }
+
+    /// SCP a file to the server, calling `progress(bytes_sent, total_bytes)` after each chunk.
+    pub fn upload_file_with_progress<F>(&self, fpath: &Path, dest: &Path, progress: F) -> Result<(), Error>
+    where
+        F: FnMut(u64, u64),
+    {
+        self.operation(None).upload_file_with_progress(fpath, dest, progress)
+    }
+
+    /// Retrieve a file from the server into memory.
+    /// Use `download_to` or `download_file` for files that may not fit.
+    pub fn get_file(&self, fpath: &Path) -> Result<(Vec<u8>, ScpFileStat), Error> {
+        self.operation(None).get_file(fpath)
+    }
+
+    /// Stream a file from the server into `out`.
+    pub fn download_to<W: Write>(&self, remote: &Path, out: W) -> Result<ScpFileStat, Error> {
+        self.operation(None).download_to(remote, out)
+    }
+
+    /// Stream a file from the server into `out`, calling `progress(bytes_received, total_bytes)`
+    /// after each chunk.
+    pub fn download_to_with_progress<W, F>(&self, remote: &Path, out: W, progress: F) -> Result<ScpFileStat, Error>
+    where
+        W: Write,
+        F: FnMut(u64, u64),
+    {
+        self.operation(None).download_to_with_progress(remote, out, progress)
+    }
+
+    /// Download a file from the server to `local`, giving it the remote file's permissions.
+    pub fn download_file(&self, remote: &Path, local: &Path) -> Result<ScpFileStat, Error> {
+        self.operation(None).download_file(remote, local)
+    }
+
+    /// Download a file from the server to `local`, calling `progress(bytes_received, total_bytes)`
+    /// after each chunk.
+    pub fn download_file_with_progress<F>(&self, remote: &Path, local: &Path, progress: F) -> Result<ScpFileStat, Error>
+    where
+        F: FnMut(u64, u64),
+    {
+        self.operation(None).download_file_with_progress(remote, local, progress)
+    }
+}
+
+/// Runs a single command or transfer with its own timeout. See `SSH::with_timeout`.
+pub struct WithTimeout<'a> {
+    ssh: &'a SSH,
+    timeout: Option<Duration>,
+}
+
+impl WithTimeout<'_> {
+    /// The session, limited to this operation's deadline, and a slot for the
+    /// operation's channel.
+    fn channel(&self) -> Result<(Driver, ChannelSlot), Error> {
+        let driver = self.ssh.sess_ref()?.for_operation(self.timeout);
+        let slot = self.ssh.channel_slot(&driver)?;
+        Ok((driver, slot))
+    }
+
+    /// See `SSH::run_command`.
+    pub fn run_command(&self, cmd: &str) -> Result<String, Error> {
+        let output = self.exec(cmd)?;
+        String::from_utf8(output.stdout).map_err(|e| Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))
+    }
+
+    /// See `SSH::exec`.
+    pub fn exec(&self, cmd: &str) -> Result<CommandOutput, Error> {
+        let (driver, slot) = self.channel()?;
+        exec::run(&driver, cmd, slot)
+    }
+
+    /// See `SSH::upload_file`.
+    pub fn upload_file(&self, fpath: &Path, dest: &Path) -> Result<(), Error> {
+        self.upload_file_with_progress(fpath, dest, |_, _| {})
+    }
+
+    /// See `SSH::upload_file_with_progress`.
+    pub fn upload_file_with_progress<F>(&self, fpath: &Path, dest: &Path, mut progress: F) -> Result<(), Error>
+    where
+        F: FnMut(u64, u64),
+    {
+        let (driver, _slot) = self.channel()?;
+        scp::upload(&driver, fpath, dest, &mut progress)
     }
 
-    /// Retrieve a file from the server.
+    /// See `SSH::get_file`.
     pub fn get_file(&self, fpath: &Path) -> Result<(Vec<u8>, ScpFileStat), Error> {
-        let sess = self.session.as_ref().unwrap();
-        let (mut remote_file, stat) = sess.scp_recv(fpath)?;
         let mut contents = Vec::new();
-        remote_file.read_to_end(&mut contents)?;
+        let stat = self.download_to(fpath, &mut contents)?;
         Ok((contents, stat))
     }
+
+    /// See `SSH::download_to`.
+    pub fn download_to<W: Write>(&self, remote: &Path, out: W) -> Result<ScpFileStat, Error> {
+        self.download_to_with_progress(remote, out, |_, _| {})
+    }
+
+    /// See `SSH::download_to_with_progress`.
+    pub fn download_to_with_progress<W, F>(&self, remote: &Path, mut out: W, mut progress: F) -> Result<ScpFileStat, Error>
+    where
+        W: Write,
+        F: FnMut(u64, u64),
+    {
+        let (driver, _slot) = self.channel()?;
+        scp::download(&driver, remote, &mut out, &mut progress)
+    }
+
+    /// See `SSH::download_file`.
+    pub fn download_file(&self, remote: &Path, local: &Path) -> Result<ScpFileStat, Error> {
+        self.download_file_with_progress(remote, local, |_, _| {})
+    }
+
+    /// See `SSH::download_file_with_progress`.
+    pub fn download_file_with_progress<F>(&self, remote: &Path, local: &Path, mut progress: F) -> Result<ScpFileStat, Error>
+    where
+        F: FnMut(u64, u64),
+    {
+        let (driver, _slot) = self.channel()?;
+        scp::download_file(&driver, remote, local, &mut progress)
+    }
 }
@@ -0,0 +1,223 @@
+use crate::error::Error;
+use crate::SSH;
+use std::ops::Deref;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+
+/// Counts the channels open on a session against `SSH::set_max_channels`.
+#[derive(Default)]
+pub(crate) struct ChannelLimit {
+    state: Mutex<Slots>,
+    freed: Condvar,
+}
+
+#[derive(Default)]
+struct Slots {
+    open: usize,
+    max: Option<usize>,
+}
+
+impl Slots {
+    fn free(&self) -> bool {
+        self.max.is_none_or(|max| self.open < max)
+    }
+}
+
+impl ChannelLimit {
+    fn lock(&self) -> MutexGuard<'_, Slots> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn set_max(&self, max: Option<usize>) {
+        self.lock().max = max.map(|max| max.max(1));
+        self.freed.notify_all();
+    }
+
+    /// Waits for a free slot, until `limit` if there is one.
+    pub(crate) fn acquire(self: &Arc<Self>, limit: Option<Instant>) -> Result<ChannelSlot, Error> {
+        let mut slots = self.lock();
+        while !slots.free() {
+            slots = match limit {
+                None => self.freed.wait(slots).unwrap_or_else(PoisonError::into_inner),
+                Some(limit) => {
+                    let left = limit.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Err(Error::Timeout);
+                    }
+                    self.freed.wait_timeout(slots, left).unwrap_or_else(PoisonError::into_inner).0
+                }
+            };
+        }
+        slots.open += 1;
+        Ok(ChannelSlot(Arc::clone(self)))
+    }
+
+    /// A free slot if there is one, for loops that cannot wait.
+    pub(crate) fn try_acquire(self: &Arc<Self>) -> Option<ChannelSlot> {
+        let mut slots = self.lock();
+        if !slots.free() {
+            return None;
+        }
+        slots.open += 1;
+        Some(ChannelSlot(Arc::clone(self)))
+    }
+}
+
+/// One open channel's share of the limit, given back on drop.
+pub(crate) struct ChannelSlot(Arc<ChannelLimit>);
+
+impl Drop for ChannelSlot {
+    fn drop(&mut self) {
+        self.0.lock().open -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// Lets one channel or subsystem open at a time be under way on a session.
+///
+/// libssh2 keeps the progress of a non-blocking open (a session channel, a
+/// `direct-tcpip` channel, SCP, SFTP or a remote listener) in the session rather
+/// than in the call, so two opens retried side by side would pick up each
+/// other's half-finished state. Starting a command or shell on a new channel
+/// counts as part of its open: a request waiting for its reply fails if it reads
+/// the reply meant for another channel first.
+#[derive(Default)]
+pub(crate) struct OpenLock {
+    busy: Mutex<bool>,
+    freed: Condvar,
+}
+
+impl OpenLock {
+    fn lock(&self) -> MutexGuard<'_, bool> {
+        self.busy.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Waits until no other open is under way, until `limit` if there is one.
+    pub(crate) fn acquire(self: &Arc<Self>, limit: Option<Instant>) -> Option<OpenGuard> {
+        let mut busy = self.lock();
+        while *busy {
+            busy = match limit {
+                None => self.freed.wait(busy).unwrap_or_else(PoisonError::into_inner),
+                Some(limit) => {
+                    let left = limit.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return None;
+                    }
+                    self.freed.wait_timeout(busy, left).unwrap_or_else(PoisonError::into_inner).0
+                }
+            };
+        }
+        *busy = true;
+        Some(OpenGuard(Arc::clone(self)))
+    }
+
+    /// Takes the lock if no other open is under way, without waiting.
+    pub(crate) fn try_acquire(self: &Arc<Self>) -> Option<OpenGuard> {
+        let mut busy = self.lock();
+        if *busy {
+            return None;
+        }
+        *busy = true;
+        Some(OpenGuard(Arc::clone(self)))
+    }
+}
+
+/// The right to drive an open on a session, given back on drop.
+pub(crate) struct OpenGuard(Arc<OpenLock>);
+
+impl Drop for OpenGuard {
+    fn drop(&mut self) {
+        *self.0.lock() = false;
+        self.0.freed.notify_one();
+    }
+}
+
+/// A handle to one `SSH` connection that threads share, like an OpenSSH
+/// ControlMaster connection: commands, transfers, tunnels and forwards started
+/// from any clone run side by side as channels of the same session.
+///
+/// Clones are cheap. The connection closes once the last clone and everything
+/// started from it are dropped. Set `SSH::set_max_channels` to make callers wait
+/// instead of having the server refuse channels past its own limit.
+#[derive(Clone)]
+pub struct SharedSSH(Arc<SSH>);
+
+impl Deref for SharedSSH {
+    type Target = SSH;
+
+    fn deref(&self) -> &SSH {
+        &self.0
+    }
+}
+
+impl From<SSH> for SharedSSH {
+    fn from(ssh: SSH) -> Self {
+        Self(Arc::new(ssh))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn opens_from_several_threads_never_overlap() {
+        let opening = Arc::new(OpenLock::default());
+        let inside = Arc::new(AtomicUsize::new(0));
+        let opened = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let (opening, inside, opened) = (Arc::clone(&opening), Arc::clone(&inside), Arc::clone(&opened));
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        let _guard = opening.acquire(None).unwrap();
+                        assert_eq!(inside.fetch_add(1, Ordering::SeqCst), 0, "two opens under way at once");
+                        thread::yield_now();
+                        inside.fetch_sub(1, Ordering::SeqCst);
+                        opened.fetch_add(1, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(opened.load(Ordering::SeqCst), 400);
+    }
+
+    #[test]
+    fn waiting_for_an_open_respects_the_limit() {
+        let opening = Arc::new(OpenLock::default());
+        let guard = opening.acquire(None).unwrap();
+        let start = Instant::now();
+        assert!(opening.acquire(Some(start + Duration::from_millis(50))).is_none());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        drop(guard);
+        assert!(opening.acquire(Some(Instant::now())).is_some());
+    }
+
+    #[test]
+    fn a_released_open_wakes_a_waiter() {
+        let opening = Arc::new(OpenLock::default());
+        let guard = opening.acquire(None).unwrap();
+        let waiter = {
+            let opening = Arc::clone(&opening);
+            thread::spawn(move || opening.acquire(Some(Instant::now() + Duration::from_secs(10))).is_some())
+        };
+        thread::sleep(Duration::from_millis(20));
+        drop(guard);
+        assert!(waiter.join().unwrap());
+    }
+
+    #[test]
+    fn trying_an_open_never_waits() {
+        let opening = Arc::new(OpenLock::default());
+        let guard = opening.try_acquire().unwrap();
+        assert!(opening.try_acquire().is_none());
+        drop(guard);
+        assert!(opening.try_acquire().is_some());
+    }
+}
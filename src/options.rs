@@ -0,0 +1,62 @@
+use std::time::Duration;
+
+/// Timeouts for connecting to a server and for using the session afterwards.
+/// Every timeout defaults to `None`, which waits for as long as it takes.
+///
+/// Running out of time fails with `Error::Timeout`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectOptions {
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) handshake_timeout: Option<Duration>,
+    pub(crate) operation_timeout: Option<Duration>,
+    pub(crate) address_family: AddressFamily,
+}
+
+/// Which addresses of a host name to try, and in what order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressFamily {
+    /// Alternate between IPv4 and IPv6, starting with whichever the resolver lists first.
+    #[default]
+    Any,
+    /// Alternate between the families, starting with IPv4.
+    PreferIpv4,
+    /// Alternate between the families, starting with IPv6.
+    PreferIpv6,
+    /// Only try IPv4 addresses.
+    Ipv4Only,
+    /// Only try IPv6 addresses.
+    Ipv6Only,
+}
+
+impl ConnectOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits how long establishing the TCP connection may take, across every
+    /// address the host name resolves to.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Limits each wait for the server during the SSH handshake.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Chooses which of the host's addresses are tried first, or at all.
+    pub fn address_family(mut self, family: AddressFamily) -> Self {
+        self.address_family = family;
+        self
+    }
+
+    /// Limits authentication and every operation on the session: a command or
+    /// transfer as a whole, and each call on an SFTP session, tunnel or streamed
+    /// command. `SSH::with_timeout` overrides it for a single command or transfer.
+    pub fn operation_timeout(mut self, timeout: Duration) -> Self {
+        self.operation_timeout = Some(timeout);
+        self
+    }
+}
@@ -0,0 +1,630 @@
+use crate::error::Error;
+use crate::forward::Worker;
+use crate::keepalive::{self, MIN_INTERVAL};
+use crate::SSH;
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the health-check thread checks whether it should stop.
+const TICK: Duration = Duration::from_millis(100);
+
+/// Called on every `SSH` object the pool creates, before it connects.
+pub type PoolSetup = Arc<dyn Fn(&mut SSH) + Send + Sync>;
+
+/// How a pooled session logs in. Part of the key sessions are pooled under, so
+/// sessions are only shared between callers using the same credentials.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum Credentials {
+    Password(String),
+    /// Keys held by the running SSH agent.
+    Agent,
+    /// A private key file. The public key is derived from it.
+    KeyFile { private_key: PathBuf, passphrase: Option<String> },
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credentials::Password(_) => f.write_str("Password(..)"),
+            Credentials::Agent => f.write_str("Agent"),
+            Credentials::KeyFile { private_key, .. } => f.debug_struct("KeyFile").field("private_key", private_key).finish_non_exhaustive(),
+        }
+    }
+}
+
+/// Limits and timeouts for an `SshPool`.
+#[derive(Clone)]
+pub struct PoolOptions {
+    pub(crate) max_per_host: usize,
+    pub(crate) idle_timeout: Duration,
+    pub(crate) health_check_interval: Duration,
+    pub(crate) acquire_timeout: Option<Duration>,
+    pub(crate) setup: Option<PoolSetup>,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            max_per_host: 8,
+            idle_timeout: Duration::from_secs(300),
+            health_check_interval: Duration::from_secs(30),
+            acquire_timeout: None,
+            setup: None,
+        }
+    }
+}
+
+impl fmt::Debug for PoolOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoolOptions")
+            .field("max_per_host", &self.max_per_host)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("health_check_interval", &self.health_check_interval)
+            .field("acquire_timeout", &self.acquire_timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PoolOptions {
+    /// Eight sessions per host, closed after five minutes idle and checked every
+    /// 30 seconds. `get` waits as long as it takes for a free slot.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many sessions may be open to one host and port at once, idle or in use,
+    /// across every user and set of credentials. At least one.
+    pub fn max_per_host(mut self, max: usize) -> Self {
+        self.max_per_host = max.max(1);
+        self
+    }
+
+    /// How long a session may sit unused before it is closed.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// How often idle sessions are sent a keepalive (at least every two seconds).
+    /// A session whose server has not answered the previous one is closed.
+    pub fn health_check_interval(mut self, interval: Duration) -> Self {
+        self.health_check_interval = interval.max(MIN_INTERVAL);
+        self
+    }
+
+    /// Limits how long `get` waits for a host that is at `max_per_host`.
+    /// Running out of time fails with `Error::Timeout`.
+    pub fn acquire_timeout(mut self, timeout: Duration) -> Self {
+        self.acquire_timeout = Some(timeout);
+        self
+    }
+
+    /// Prepares every new `SSH` object before it connects, e.g. to set host key
+    /// checking, connect options or a keepalive policy.
+    pub fn setup<F>(mut self, setup: F) -> Self
+    where
+        F: Fn(&mut SSH) + Send + Sync + 'static,
+    {
+        self.setup = Some(Arc::new(setup));
+        self
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Key {
+    host: String,
+    port: u16,
+    user: String,
+    credentials: Credentials,
+}
+
+impl Key {
+    fn target(&self) -> (String, u16) {
+        (self.host.clone(), self.port)
+    }
+}
+
+struct Idle {
+    ssh: SSH,
+    since: Instant,
+    /// When the last health-check keepalive went out.
+    probed: Option<Instant>,
+    /// When a health check last looked at the session.
+    checked: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    idle: HashMap<Key, Vec<Idle>>,
+    /// Sessions per host and port: idle, handed out, or being connected.
+    open: HashMap<(String, u16), usize>,
+}
+
+impl State {
+    fn open(&self, target: &(String, u16)) -> usize {
+        self.open.get(target).copied().unwrap_or(0)
+    }
+
+    fn release(&mut self, target: &(String, u16)) {
+        if let Some(n) = self.open.get_mut(target) {
+            *n -= 1;
+            if *n == 0 {
+                self.open.remove(target);
+            }
+        }
+    }
+
+    /// Takes the longest idle session to `target` under any key.
+    fn oldest_idle(&mut self, target: &(String, u16)) -> Option<SSH> {
+        let key = self
+            .idle
+            .iter()
+            .filter(|(key, list)| key.host == target.0 && key.port == target.1 && !list.is_empty())
+            .min_by_key(|(_, list)| list[0].since)
+            .map(|(key, _)| key.clone())?;
+        let list = self.idle.get_mut(&key)?;
+        let idle = list.remove(0);
+        if list.is_empty() {
+            self.idle.remove(&key);
+        }
+        Some(idle.ssh)
+    }
+}
+
+type Connect = Box<dyn Fn(&Key) -> Result<SSH, Error> + Send + Sync>;
+type Probe = Box<dyn Fn(&mut Idle, Duration) -> bool + Send + Sync>;
+
+/// How the pool opens, checks and probes sessions. Tests swap in fakes that need
+/// no server.
+struct Connector {
+    connect: Connect,
+    /// Whether a session can be handed out or kept.
+    usable: fn(&SSH) -> bool,
+    probe: Probe,
+}
+
+impl Connector {
+    /// Logs in over the network, preparing each `SSH` object with `setup`.
+    fn network(setup: Option<PoolSetup>) -> Self {
+        Self {
+            connect: Box::new(move |key| connect(setup.as_ref(), key)),
+            usable: SSH::authed,
+            probe: Box::new(probe),
+        }
+    }
+}
+
+struct Inner {
+    options: PoolOptions,
+    connector: Connector,
+    state: Mutex<State>,
+    freed: Condvar,
+}
+
+impl Inner {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Takes a session back from a guard. Broken ones are closed.
+    fn put_back(&self, key: Key, ssh: SSH, keep: bool) {
+        let mut state = self.lock();
+        if keep && (self.connector.usable)(&ssh) {
+            let now = Instant::now();
+            let idle = Idle {
+                ssh,
+                since: now,
+                probed: None,
+                checked: now,
+            };
+            state.idle.entry(key).or_default().push(idle);
+        } else {
+            state.release(&key.target());
+            drop(state);
+            drop(ssh);
+        }
+        self.freed.notify_all();
+    }
+
+    /// Closes idle sessions that timed out or whose server stopped answering, and
+    /// sends the rest a keepalive.
+    fn check_idle(&self) {
+        let round = Instant::now();
+        let mut expired = Vec::new();
+        let mut state = self.lock();
+        for (key, list) in state.idle.iter_mut() {
+            let (old, fresh): (Vec<Idle>, Vec<Idle>) =
+                mem::take(list).into_iter().partition(|entry| entry.since.elapsed() >= self.options.idle_timeout);
+            *list = fresh;
+            expired.extend(old.into_iter().map(|entry| (key.target(), entry.ssh)));
+        }
+        state.idle.retain(|_, list| !list.is_empty());
+        for (target, _) in &expired {
+            state.release(target);
+        }
+        drop(state);
+        self.freed.notify_all();
+        drop(expired);
+
+        // Probes wait on the network, so they run without the lock, taking out one
+        // session at a time. It still counts as open; the others stay available.
+        loop {
+            let mut state = self.lock();
+            let next = state
+                .idle
+                .iter()
+                .find_map(|(key, list)| list.iter().position(|entry| entry.checked < round).map(|i| (key.clone(), i)));
+            let (key, i) = match next {
+                Some(next) => next,
+                None => return,
+            };
+            let mut entry = take_idle(&mut state, &key, i);
+            drop(state);
+            let healthy = (self.connector.probe)(&mut entry, self.options.health_check_interval);
+            entry.checked = Instant::now();
+            let mut state = self.lock();
+            if healthy {
+                // Keep each list oldest first.
+                let list = state.idle.entry(key).or_default();
+                let at = list.partition_point(|other| other.since <= entry.since);
+                list.insert(at, entry);
+            } else {
+                state.release(&key.target());
+                drop(state);
+                self.freed.notify_all();
+                drop(entry);
+            }
+        }
+    }
+}
+
+/// Removes the `i`th idle session under `key`, dropping the list once it is empty.
+fn take_idle(state: &mut State, key: &Key, i: usize) -> Idle {
+    let list = state.idle.get_mut(key).expect("listed by the caller");
+    let entry = list.remove(i);
+    if list.is_empty() {
+        state.idle.remove(key);
+    }
+    entry
+}
+
+/// Logs in to the host named by `key`.
+fn connect(setup: Option<&PoolSetup>, key: &Key) -> Result<SSH, Error> {
+    let mut ssh = SSH::new(&key.host, key.port);
+    if let Some(setup) = setup {
+        setup(&mut ssh);
+    }
+    match &key.credentials {
+        Credentials::Password(pass) => ssh.connect(&key.user, pass)?,
+        Credentials::Agent => ssh.connect_agent(&key.user)?,
+        Credentials::KeyFile { private_key, passphrase } => {
+            ssh.connect_with_key(&key.user, private_key, None, passphrase.as_deref())?
+        }
+    }
+    Ok(ssh)
+}
+
+/// Whether an idle session is still usable. Sends it the next keepalive if so.
+fn probe(entry: &mut Idle, interval: Duration) -> bool {
+    let driver = match entry.ssh.session.get().filter(|d| d.is_alive() && d.sess.authenticated()) {
+        Some(driver) => driver,
+        None => return false,
+    };
+    if let Some(probed) = entry.probed {
+        if probed.elapsed() < interval {
+            return true;
+        }
+        if !keepalive::answered(&driver, probed.elapsed()) {
+            return false;
+        }
+    }
+    entry.probed = Some(Instant::now());
+    keepalive::probe(&driver, interval).is_ok()
+}
+
+/// Keeps authenticated sessions open for reuse, so that each task does not pay
+/// for a handshake and login of its own.
+///
+/// Sessions are pooled per host, port, user and credentials. Idle ones are sent a
+/// keepalive every `health_check_interval` and closed once the server stops
+/// answering or they have been idle for `idle_timeout`. Clones share one pool; the
+/// last clone to go closes every idle session.
+#[derive(Clone)]
+pub struct SshPool {
+    inner: Arc<Inner>,
+    _checker: Arc<Worker>,
+}
+
+impl SshPool {
+    /// Starts the pool's health-check thread.
+    pub fn new(options: PoolOptions) -> Self {
+        let connector = Connector::network(options.setup.clone());
+        Self::with_connector(options, connector)
+    }
+
+    fn with_connector(options: PoolOptions, connector: Connector) -> Self {
+        let inner = Arc::new(Inner {
+            options,
+            connector,
+            state: Mutex::default(),
+            freed: Condvar::new(),
+        });
+        let interval = inner.options.health_check_interval;
+        let weak = Arc::downgrade(&inner);
+        // The thread holds no strong reference between checks, so the pool's idle
+        // sessions close once the last handle and guard are gone.
+        let checker = Worker::spawn(move |shared| {
+            let mut last = Instant::now();
+            while !shared.stopped() {
+                thread::sleep(TICK);
+                if last.elapsed() < interval {
+                    continue;
+                }
+                last = Instant::now();
+                match weak.upgrade() {
+                    Some(inner) => inner.check_idle(),
+                    None => break,
+                }
+            }
+            Ok(())
+        });
+        Self {
+            inner,
+            _checker: Arc::new(checker),
+        }
+    }
+
+    /// A session to `host:port` logged in as `user`: an idle one if there is a
+    /// healthy one, otherwise a new connection.
+    ///
+    /// When the host already has `max_per_host` sessions, the longest idle one for
+    /// other credentials is closed to make room; if every one is in use, waits for
+    /// one to be returned.
+    pub fn get(&self, host: &str, port: u16, user: &str, credentials: &Credentials) -> Result<PooledSSH, Error> {
+        let key = Key {
+            host: host.to_string(),
+            port,
+            user: user.to_string(),
+            credentials: credentials.clone(),
+        };
+        let target = key.target();
+        let deadline = self.inner.options.acquire_timeout.map(|t| Instant::now() + t);
+        let mut state = self.inner.lock();
+        loop {
+            // Closing a session blocks, so broken and evicted ones are dropped unlocked.
+            let mut stale = Vec::new();
+            while let Some(idle) = state.idle.get_mut(&key).and_then(Vec::pop) {
+                if (self.inner.connector.usable)(&idle.ssh) {
+                    drop(state);
+                    drop(stale);
+                    return Ok(self.guard(key, idle.ssh));
+                }
+                state.release(&target);
+                stale.push(idle.ssh);
+            }
+            state.idle.remove(&key);
+            if state.open(&target) < self.inner.options.max_per_host {
+                *state.open.entry(target.clone()).or_default() += 1;
+                drop(state);
+                drop(stale);
+                return match (self.inner.connector.connect)(&key) {
+                    Ok(ssh) => Ok(self.guard(key, ssh)),
+                    Err(e) => {
+                        self.inner.lock().release(&target);
+                        self.inner.freed.notify_all();
+                        Err(e)
+                    }
+                };
+            }
+            if let Some(ssh) = state.oldest_idle(&target) {
+                state.release(&target);
+                stale.push(ssh);
+            }
+            if !stale.is_empty() {
+                drop(state);
+                drop(stale);
+                state = self.inner.lock();
+                continue;
+            }
+            state = match deadline {
+                None => self.inner.freed.wait(state).unwrap_or_else(PoisonError::into_inner),
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Err(Error::Timeout);
+                    }
+                    self.inner.freed.wait_timeout(state, left).unwrap_or_else(PoisonError::into_inner).0
+                }
+            };
+        }
+    }
+
+    /// Sessions waiting in the pool, across every host.
+    pub fn idle_count(&self) -> usize {
+        self.inner.lock().idle.values().map(Vec::len).sum()
+    }
+
+    /// Sessions open to `host:port`, idle or in use.
+    pub fn open_count(&self, host: &str, port: u16) -> usize {
+        self.inner.lock().open(&(host.to_string(), port))
+    }
+
+    /// Closes every idle session. Sessions in use are unaffected.
+    pub fn clear(&self) {
+        let mut state = self.inner.lock();
+        let idle: Vec<_> = state.idle.drain().collect();
+        for (key, list) in &idle {
+            for _ in list {
+                state.release(&key.target());
+            }
+        }
+        drop(state);
+        drop(idle);
+        self.inner.freed.notify_all();
+    }
+
+    fn guard(&self, key: Key, ssh: SSH) -> PooledSSH {
+        PooledSSH {
+            ssh: Some(ssh),
+            key,
+            keep: true,
+            pool: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl Default for SshPool {
+    fn default() -> Self {
+        Self::new(PoolOptions::default())
+    }
+}
+
+/// A session borrowed from an `SshPool`, returned to it when dropped.
+///
+/// A session that is no longer connected is closed instead of returned. Only the
+/// `&self` methods of `SSH` are reachable, so a borrowed session cannot be logged
+/// in again as someone else and then handed to the next caller under the old key.
+pub struct PooledSSH {
+    ssh: Option<SSH>,
+    key: Key,
+    keep: bool,
+    pool: Arc<Inner>,
+}
+
+impl PooledSSH {
+    /// Closes the session instead of returning it to the pool, e.g. after it was
+    /// left in an unknown state.
+    pub fn discard(mut self) {
+        self.keep = false;
+    }
+}
+
+impl Deref for PooledSSH {
+    type Target = SSH;
+
+    fn deref(&self) -> &SSH {
+        self.ssh.as_ref().expect("session taken before drop")
+    }
+}
+
+impl Drop for PooledSSH {
+    fn drop(&mut self) {
+        if let Some(ssh) = self.ssh.take() {
+            self.pool.put_back(self.key.clone(), ssh, self.keep);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A pool whose sessions are unconnected `SSH` objects, probed by `probe`.
+    /// Also returns how many sessions it has opened.
+    fn fake_pool<P>(options: PoolOptions, probe: P) -> (SshPool, Arc<AtomicUsize>)
+    where
+        P: Fn(&mut Idle, Duration) -> bool + Send + Sync + 'static,
+    {
+        let opened = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&opened);
+        let connector = Connector {
+            connect: Box::new(move |key| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(SSH::new(&key.host, key.port))
+            }),
+            usable: |_| true,
+            probe: Box::new(probe),
+        };
+        (SshPool::with_connector(options, connector), opened)
+    }
+
+    fn password(pass: &str) -> Credentials {
+        Credentials::Password(pass.to_owned())
+    }
+
+    #[test]
+    fn dropped_sessions_are_returned_and_reused() {
+        let (pool, opened) = fake_pool(PoolOptions::new(), |_, _| true);
+        drop(pool.get("host", 22, "user", &password("pw")).unwrap());
+        assert_eq!((pool.idle_count(), pool.open_count("host", 22)), (1, 1));
+
+        let ssh = pool.get("host", 22, "user", &password("pw")).unwrap();
+        assert_eq!(opened.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.idle_count(), 0);
+        ssh.discard();
+        assert_eq!((pool.idle_count(), pool.open_count("host", 22)), (0, 0));
+
+        // Other credentials never get the session.
+        drop(pool.get("host", 22, "user", &password("pw")).unwrap());
+        drop(pool.get("host", 22, "user", &password("other")).unwrap());
+        assert_eq!(opened.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn idle_sessions_expire() {
+        let (pool, _) = fake_pool(PoolOptions::new().idle_timeout(Duration::ZERO), |_, _| true);
+        drop(pool.get("host", 22, "user", &password("pw")).unwrap());
+        pool.inner.check_idle();
+        assert_eq!((pool.idle_count(), pool.open_count("host", 22)), (0, 0));
+    }
+
+    #[test]
+    fn unanswered_probes_close_sessions() {
+        let (pool, _) = fake_pool(PoolOptions::new(), |entry, _| entry.ssh.port != 2222);
+        drop(pool.get("host", 22, "user", &password("pw")).unwrap());
+        drop(pool.get("host", 2222, "user", &password("pw")).unwrap());
+        pool.inner.check_idle();
+        assert_eq!(pool.idle_count(), 1);
+        assert_eq!((pool.open_count("host", 22), pool.open_count("host", 2222)), (1, 0));
+    }
+
+    #[test]
+    fn per_host_cap_waits_then_evicts_idle_sessions() {
+        let options = PoolOptions::new().max_per_host(1).acquire_timeout(Duration::from_millis(200));
+        let (pool, opened) = fake_pool(options, |_, _| true);
+        let first = pool.get("host", 22, "user", &password("a")).unwrap();
+        assert!(matches!(pool.get("host", 22, "user", &password("b")), Err(Error::Timeout)));
+        // Another host has a cap of its own.
+        drop(pool.get("other", 22, "user", &password("b")).unwrap());
+
+        drop(first);
+        let second = pool.get("host", 22, "user", &password("b")).unwrap();
+        assert_eq!(pool.open_count("host", 22), 1);
+        assert_eq!(opened.load(Ordering::SeqCst), 3);
+
+        let waiter = {
+            let pool = pool.clone();
+            thread::spawn(move || pool.get("host", 22, "user", &password("b")).is_ok())
+        };
+        thread::sleep(Duration::from_millis(20));
+        drop(second);
+        assert!(waiter.join().unwrap());
+    }
+
+    #[test]
+    fn health_checks_leave_other_sessions_available() {
+        let (pool, opened) = fake_pool(PoolOptions::new(), |_, _| {
+            thread::sleep(Duration::from_millis(200));
+            true
+        });
+        let sessions: Vec<_> = (0..3).map(|_| pool.get("host", 22, "user", &password("pw")).unwrap()).collect();
+        drop(sessions);
+        let checker = {
+            let inner = Arc::clone(&pool.inner);
+            thread::spawn(move || inner.check_idle())
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(pool.idle_count(), 2);
+        drop(pool.get("host", 22, "user", &password("pw")).unwrap());
+        assert_eq!(opened.load(Ordering::SeqCst), 3);
+        checker.join().unwrap();
+        assert_eq!(pool.idle_count(), 3);
+    }
+}
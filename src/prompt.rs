@@ -0,0 +1,212 @@
+use ssh2::KeyboardInteractivePrompt;
+use std::iter::FromIterator;
+
+pub use ssh2::Prompt;
+
+/// Answers the prompts a server sends during keyboard-interactive authentication
+/// (PAM passwords, one-time codes and the like).
+pub trait Prompter {
+    /// Called once per round of prompts. `instructions` may be empty, and a round may
+    /// have no prompts at all. Must return one response per prompt, in order.
+    fn prompt(&mut self, username: &str, instructions: &str, prompts: &[Prompt]) -> Vec<String>;
+}
+
+/// Adapts a `Prompter` to the callback libssh2 expects.
+pub(crate) struct Adapter<'p, P: Prompter + ?Sized>(pub(crate) &'p mut P);
+
+impl<P: Prompter + ?Sized> KeyboardInteractivePrompt for Adapter<'_, P> {
+    fn prompt<'a>(&mut self, username: &str, instructions: &str, prompts: &[Prompt<'a>]) -> Vec<String> {
+        self.0.prompt(username, instructions, prompts)
+    }
+}
+
+/// Answers prompts from a fixed list, for unattended logins.
+///
+/// A prompt gets the response of the entry whose text equals it, ignoring
+/// surrounding whitespace. Failing that it gets the longest entry it contains, so
+/// `"Password"` matches `"Password: "` but `"New password"` wins for
+/// `"New password: "`. Among equally good entries the first one added wins.
+/// Entries that are empty after trimming never match, and unknown prompts get an
+/// empty response.
+#[derive(Debug, Clone, Default)]
+pub struct StaticPrompter {
+    answers: Vec<(String, String)>,
+}
+
+impl StaticPrompter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers prompts equal to or containing `prompt` with `response`.
+    pub fn answer(mut self, prompt: &str, response: &str) -> Self {
+        self.answers.push((prompt.to_owned(), response.to_owned()));
+        self
+    }
+
+    fn lookup(&self, text: &str) -> &str {
+        let text = text.trim();
+        let mut best: Option<&(String, String)> = None;
+        for entry in &self.answers {
+            let key = entry.0.trim();
+            if key.is_empty() || !text.contains(key) {
+                continue;
+            }
+            if key == text {
+                return &entry.1;
+            }
+            if best.is_none_or(|(best, _)| key.len() > best.trim().len()) {
+                best = Some(entry);
+            }
+        }
+        best.map_or("", |(_, response)| response.as_str())
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for StaticPrompter {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Self {
+            answers: iter.into_iter().map(|(k, v)| (k.into(), v.into())).collect(),
+        }
+    }
+}
+
+impl Prompter for StaticPrompter {
+    fn prompt(&mut self, _username: &str, _instructions: &str, prompts: &[Prompt]) -> Vec<String> {
+        prompts.iter().map(|p| self.lookup(&p.text).to_owned()).collect()
+    }
+}
+
+/// Asks the person at the controlling terminal, hiding input for prompts that
+/// should not be echoed. If the terminal cannot be used the responses are empty,
+/// which the server treats as a failed attempt.
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TtyPrompter;
+
+#[cfg(unix)]
+impl Prompter for TtyPrompter {
+    fn prompt(&mut self, _username: &str, instructions: &str, prompts: &[Prompt]) -> Vec<String> {
+        tty::ask(instructions, prompts).unwrap_or_else(|_| vec![String::new(); prompts.len()])
+    }
+}
+
+#[cfg(unix)]
+mod tty {
+    use super::Prompt;
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    pub(super) fn ask(instructions: &str, prompts: &[Prompt]) -> io::Result<Vec<String>> {
+        let tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+        let mut out = &tty;
+        let mut reader = BufReader::new(&tty);
+        if !instructions.is_empty() {
+            writeln!(out, "{}", instructions)?;
+        }
+        let mut responses = Vec::with_capacity(prompts.len());
+        for prompt in prompts {
+            write!(out, "{}", prompt.text)?;
+            out.flush()?;
+            let mut line = String::new();
+            if prompt.echo {
+                reader.read_line(&mut line)?;
+            } else {
+                let _hidden = NoEcho::enable(&tty)?;
+                reader.read_line(&mut line)?;
+                // The newline the user typed was not echoed either.
+                writeln!(out)?;
+            }
+            let len = line.trim_end_matches(&['\r', '\n'][..]).len();
+            line.truncate(len);
+            responses.push(line);
+        }
+        Ok(responses)
+    }
+
+    /// Turns off echo on a terminal, restoring it on drop.
+    struct NoEcho<'a> {
+        tty: &'a File,
+        saved: libc::termios,
+    }
+
+    impl<'a> NoEcho<'a> {
+        fn enable(tty: &'a File) -> io::Result<Self> {
+            let mut saved: libc::termios = unsafe { mem::zeroed() };
+            if unsafe { libc::tcgetattr(tty.as_raw_fd(), &mut saved) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut quiet = saved;
+            quiet.c_lflag &= !libc::ECHO;
+            if unsafe { libc::tcsetattr(tty.as_raw_fd(), libc::TCSANOW, &quiet) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self { tty, saved })
+        }
+    }
+
+    impl Drop for NoEcho<'_> {
+        fn drop(&mut self) {
+            unsafe { libc::tcsetattr(self.tty.as_raw_fd(), libc::TCSANOW, &self.saved) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ask(prompter: &mut StaticPrompter, texts: &[&str]) -> Vec<String> {
+        let prompts: Vec<Prompt> = texts
+            .iter()
+            .map(|text| Prompt {
+                text: (*text).into(),
+                echo: false,
+            })
+            .collect();
+        prompter.prompt("user", "", &prompts)
+    }
+
+    #[test]
+    fn substring_match() {
+        let mut answers = StaticPrompter::new().answer("Password", "pw").answer("Verification code", "123456");
+        assert_eq!(ask(&mut answers, &["Password: ", "Verification code: "]), vec!["pw", "123456"]);
+    }
+
+    #[test]
+    fn exact_match_wins_over_earlier_substring() {
+        let mut answers = StaticPrompter::new().answer("code", "first").answer(" Enter code: ", "exact");
+        assert_eq!(ask(&mut answers, &["Enter code:"]), vec!["exact"]);
+    }
+
+    #[test]
+    fn longest_substring_wins() {
+        let mut answers = StaticPrompter::new().answer("password", "old").answer("New password", "new");
+        assert_eq!(
+            ask(&mut answers, &["New password: ", "Retype New password: ", "Current password: "]),
+            vec!["new", "new", "old"]
+        );
+    }
+
+    #[test]
+    fn ties_go_to_the_first_entry() {
+        let mut answers = StaticPrompter::new().answer("Token", "a").answer("token", "b").answer("Token", "c");
+        assert_eq!(ask(&mut answers, &["Token and token: "]), vec!["a"]);
+        assert_eq!(ask(&mut answers, &["Token"]), vec!["a"]);
+    }
+
+    #[test]
+    fn empty_entries_never_match() {
+        let mut answers = StaticPrompter::new().answer("", "x").answer("  ", "y").answer("OTP", "otp");
+        assert_eq!(ask(&mut answers, &["Password: ", "OTP: ", ""]), vec!["", "otp", ""]);
+    }
+
+    #[test]
+    fn unknown_prompts_get_empty_responses() {
+        let mut answers: StaticPrompter = vec![("Password", "pw")].into_iter().collect();
+        assert_eq!(ask(&mut answers, &["Passcode: ", "password: "]), vec!["", ""]);
+        assert!(ask(&mut answers, &[]).is_empty());
+    }
+}
@@ -0,0 +1,124 @@
+use crate::error::Error;
+use crate::forward::{loopback_pair, Worker};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the command is checked for having exited.
+const EXIT_POLL: Duration = Duration::from_millis(10);
+
+/// How long to wait for the rest of the command's stderr once it has exited.
+const STDERR_GRACE: Duration = Duration::from_millis(100);
+
+/// How much of the command's stderr is kept for the error message.
+const STDERR_TAIL: usize = 4096;
+
+/// Runs a ProxyCommand through the shell and relays a loopback connection to its
+/// stdin and stdout, so that a session can run over it. Returns the end to hand to
+/// libssh2 and the worker supervising the command.
+///
+/// The worker kills the command when stopped. If the command exits first, the
+/// worker ends with `Error::Proxy`, carrying its exit status and the last of its stderr.
+pub(crate) fn spawn(command: &str) -> Result<(TcpStream, Worker), Error> {
+    let (socket, local) = loopback_pair()?;
+    let mut child = shell(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::Proxy(format!("cannot run `{}`: {}", command, e)))?;
+    let (mut stdin, mut stdout, mut stderr) = match (child.stdin.take(), child.stdout.take(), child.stderr.take()) {
+        (Some(stdin), Some(stdout), Some(stderr)) => (stdin, stdout, stderr),
+        _ => unreachable!("all three streams are piped"),
+    };
+
+    // Blocking copies on their own threads; they end when either side closes.
+    let mut to_child = local.try_clone()?;
+    let mut from_child = local.try_clone()?;
+    thread::spawn(move || {
+        let _ = copy(&mut to_child, &mut stdin);
+    });
+    thread::spawn(move || {
+        let _ = copy(&mut stdout, &mut from_child);
+        let _ = from_child.shutdown(Shutdown::Write);
+    });
+    let tail = Arc::new(Mutex::new(Vec::new()));
+    let collected = Arc::clone(&tail);
+    let errors = thread::spawn(move || {
+        let mut buf = [0; 1024];
+        while let Ok(n @ 1..) = stderr.read(&mut buf) {
+            if let Ok(mut tail) = collected.lock() {
+                tail.extend_from_slice(&buf[..n]);
+                let excess = tail.len().saturating_sub(STDERR_TAIL);
+                tail.drain(..excess);
+            }
+        }
+    });
+
+    let command = command.to_owned();
+    let worker = Worker::spawn(move |shared| {
+        let status = loop {
+            match child.try_wait() {
+                Ok(None) if !shared.stopped() => thread::sleep(EXIT_POLL),
+                Ok(status) => break status,
+                Err(_) => break None,
+            }
+        };
+        if status.is_none() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        // Also wakes the thread feeding stdin, which may be blocked reading `local`.
+        let _ = local.shutdown(Shutdown::Both);
+        let status = match status {
+            Some(status) => status,
+            None => return Ok(()),
+        };
+        let deadline = Instant::now() + STDERR_GRACE;
+        while !errors.is_finished() && Instant::now() < deadline {
+            thread::sleep(EXIT_POLL);
+        }
+        let tail = tail.lock().map(|tail| String::from_utf8_lossy(&tail).trim().to_owned()).unwrap_or_default();
+        let mut msg = format!("`{}` exited ({})", command, status);
+        if !tail.is_empty() {
+            msg.push_str(": ");
+            msg.push_str(&tail);
+        }
+        Err(Error::Proxy(msg))
+    });
+    Ok((socket, worker))
+}
+
+/// Copies until EOF, passing each chunk on as soon as it arrives. (`io::copy` may
+/// splice a socket into a pipe, which holds data back waiting for more.)
+fn copy<R: Read, W: Write>(from: &mut R, to: &mut W) -> io::Result<()> {
+    let mut buf = vec![0; 32 * 1024];
+    loop {
+        match from.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => to.write_all(&buf[..n])?,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Runs `command` the way OpenSSH does, through the user's shell.
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let shell = std::env::var_os("SHELL").filter(|s| !s.is_empty()).unwrap_or_else(|| "/bin/sh".into());
+    let mut cmd = Command::new(shell);
+    cmd.arg("-c").arg(format!("exec {}", command));
+    cmd
+}
+
+/// Runs `command` through `cmd.exe`.
+#[cfg(not(unix))]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
@@ -0,0 +1,201 @@
+use crate::auth::AuthChain;
+use crate::error::Error;
+use std::time::Duration;
+
+/// When and how often an `SSH` object re-establishes a lost connection.
+///
+/// With a policy set, the first operation after the connection drops logs in again
+/// with the credentials the last successful `connect*` call used, and forwards started
+/// from the `SSH` object carry on over the new session. Attempts are spaced by a delay
+/// that starts at `initial_delay` and doubles up to `max_delay`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    pub(crate) max_attempts: u32,
+    pub(crate) initial_delay: Duration,
+    pub(crate) max_delay: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Five attempts, one second apart at first and at most 30 seconds apart.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many times to try before giving up. At least one attempt is always made.
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = attempts;
+        self
+    }
+
+    /// The wait before the second attempt. The first one is made straight away.
+    pub fn initial_delay(mut self, delay: Duration) -> Self {
+        self.initial_delay = delay;
+        self
+    }
+
+    /// The longest wait between attempts.
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// The wait before attempt number `attempt`, counting from 1.
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        if attempt <= 1 {
+            return Duration::ZERO;
+        }
+        let factor = 1u32.checked_shl(attempt - 2).unwrap_or(u32::MAX);
+        self.initial_delay.saturating_mul(factor).min(self.max_delay)
+    }
+}
+
+/// Progress of a reconnect, reported to the callback set with `SSH::on_reconnect`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ReconnectEvent<'a> {
+    /// The connection was found to be gone and reconnecting starts.
+    Disconnected,
+    /// An attempt is about to be made, after waiting `delay`.
+    Attempt { attempt: u32, delay: Duration },
+    /// An attempt failed.
+    AttemptFailed { attempt: u32, error: &'a Error },
+    /// The session is back, after `attempts` attempts.
+    Reconnected { attempts: u32 },
+    /// No more attempts will be made; the operation fails with the last error.
+    GaveUp { attempts: u32 },
+}
+
+/// Observes reconnects, e.g. for logging.
+pub type ReconnectCallback = Box<dyn Fn(&ReconnectEvent) + Send + Sync>;
+
+/// How the last successful login was made, to replay it after a reconnect.
+pub(crate) struct Login {
+    pub(crate) username: String,
+    pub(crate) chain: AuthChain,
+}
+
+/// Errors that another attempt would only repeat.
+pub(crate) fn is_permanent(err: &Error) -> bool {
+    matches!(
+        err,
+        Error::AuthFailed { .. } | Error::HostKeyMismatch { .. } | Error::HostKeyRejected { .. } | Error::Config(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ssh2::ErrorCode;
+    use std::io;
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    #[test]
+    fn first_attempt_is_immediate() {
+        let policy = ReconnectPolicy::new();
+        assert_eq!(policy.delay(0), Duration::ZERO);
+        assert_eq!(policy.delay(1), Duration::ZERO);
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_cap() {
+        let policy = ReconnectPolicy::new();
+        let delays: Vec<_> = (2..=9).map(|attempt| policy.delay(attempt)).collect();
+        assert_eq!(delays, vec![secs(1), secs(2), secs(4), secs(8), secs(16), secs(30), secs(30), secs(30)]);
+    }
+
+    #[test]
+    fn custom_delays() {
+        let policy = ReconnectPolicy::new()
+            .initial_delay(Duration::from_millis(250))
+            .max_delay(secs(1));
+        assert_eq!(policy.delay(2), Duration::from_millis(250));
+        assert_eq!(policy.delay(3), Duration::from_millis(500));
+        assert_eq!(policy.delay(4), secs(1));
+        assert_eq!(policy.delay(5), secs(1));
+    }
+
+    #[test]
+    fn high_attempt_counts_stay_at_the_cap() {
+        let policy = ReconnectPolicy::new();
+        for attempt in [33, 34, 64, 1000, u32::MAX] {
+            assert_eq!(policy.delay(attempt), secs(30), "attempt {}", attempt);
+        }
+        let uncapped = ReconnectPolicy::new().initial_delay(secs(u64::MAX / 2)).max_delay(Duration::MAX);
+        assert_eq!(uncapped.delay(u32::MAX), Duration::MAX);
+    }
+
+    fn ssh_error() -> ssh2::Error {
+        ssh2::Error::new(ErrorCode::Session(-7), "unable to send")
+    }
+
+    #[test]
+    fn permanent_errors() {
+        let permanent = [
+            Error::AuthFailed {
+                methods_tried: vec!["password".to_owned()],
+            },
+            Error::HostKeyMismatch {
+                host: "host".to_owned(),
+                expected: "SHA256:a".to_owned(),
+                actual: "SHA256:b".to_owned(),
+            },
+            Error::HostKeyRejected {
+                host: "host".to_owned(),
+                fingerprint: "SHA256:a".to_owned(),
+            },
+            Error::Config("no HostName".to_owned()),
+        ];
+        for err in &permanent {
+            assert!(is_permanent(err), "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn transient_errors() {
+        let transient = [
+            Error::NotConnected,
+            Error::ConnectionLost,
+            Error::Connect(io::ErrorKind::ConnectionRefused.into()),
+            Error::ConnectFailed {
+                host: "host".to_owned(),
+                attempts: Vec::new(),
+            },
+            Error::Handshake(ssh_error()),
+            Error::Channel(ssh_error()),
+            Error::Scp(ssh_error()),
+            Error::Sftp(ssh_error()),
+            Error::RemoteExit { status: 1, signal: None },
+            Error::Ssh(ssh_error()),
+            Error::Io(io::ErrorKind::BrokenPipe.into()),
+            Error::Proxy("exited".to_owned()),
+            Error::Timeout,
+        ];
+        for err in &transient {
+            assert!(!is_permanent(err), "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn only_a_refused_login_is_permanent() {
+        let refused = ssh2::Error::new(ErrorCode::Session(-18), "Authentication failed");
+        assert!(is_permanent(&Error::auth("password", refused)));
+        let dropped = ssh2::Error::new(ErrorCode::Session(-13), "socket disconnect");
+        assert!(matches!(Error::auth("password", dropped), Error::ConnectionLost));
+        let timed_out = ssh2::Error::new(ErrorCode::Session(-9), "timed out");
+        assert!(matches!(Error::auth("publickey", timed_out), Error::Timeout));
+        assert!(!is_permanent(&Error::auth("publickey", ssh_error())));
+    }
+}
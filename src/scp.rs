@@ -0,0 +1,189 @@
+use crate::driver::Driver;
+use crate::error::Error;
+use ssh2::{Channel, ScpFileStat};
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Mode used for uploads when the local permissions cannot be read.
+#[cfg(not(unix))]
+const SCPMODE: i32 = 0o644; // chmod 644
+
+/// Size of each piece a transfer is split into.
+pub(crate) const CHUNK_SIZE: usize = 32 * 1024;
+
+/// Status byte that ends a file in the SCP protocol. Without it the remote
+/// `scp -t` sees a lost connection and never applies the timestamps.
+pub(crate) const END_OF_FILE: [u8; 1] = [0];
+
+/// Streams a local file to `dest` in chunks, keeping its mode and timestamps.
+/// `progress` is called with (bytes sent, total bytes) after every chunk.
+pub(crate) fn upload(driver: &Driver, fpath: &Path, dest: &Path, progress: &mut dyn FnMut(u64, u64)) -> Result<(), Error> {
+    let mut file = File::open(fpath)?;
+    let meta = file.metadata()?;
+    let total = meta.len();
+
+    let mut channel = driver
+        .open(|| driver.sess.scp_send(dest, mode(&meta), total, times(&meta)))
+        .map_err(Error::scp)?;
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut sent = 0;
+    while sent < total {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            let _ = driver.close(&mut channel);
+            return Err(truncated());
+        }
+        let n = n.min((total - sent) as usize);
+        driver.write_all(&mut channel, &buf[..n])?;
+        sent += n as u64;
+        progress(sent, total);
+    }
+    driver.write_all(&mut channel, &END_OF_FILE)?;
+    driver.retry(|| channel.send_eof()).map_err(Error::scp)?;
+    driver.retry(|| channel.wait_eof()).map_err(Error::scp)?;
+    let _ = driver.close(&mut channel);
+    Ok(())
+}
+
+/// Streams `remote` into `out` in chunks. `progress` is called with
+/// (bytes received, total bytes) after every chunk.
+pub(crate) fn download(
+    driver: &Driver,
+    remote: &Path,
+    out: &mut dyn Write,
+    progress: &mut dyn FnMut(u64, u64),
+) -> Result<ScpFileStat, Error> {
+    let (channel, stat) = driver.open(|| driver.sess.scp_recv(remote)).map_err(Error::scp)?;
+    receive(driver, channel, stat, out, progress)
+}
+
+/// Downloads `remote` to `local`, creating or truncating it with the remote file's mode.
+pub(crate) fn download_file(
+    driver: &Driver,
+    remote: &Path,
+    local: &Path,
+    progress: &mut dyn FnMut(u64, u64),
+) -> Result<ScpFileStat, Error> {
+    // Only touch the local file once the server has agreed to send the remote one.
+    let (channel, stat) = driver.open(|| driver.sess.scp_recv(remote)).map_err(Error::scp)?;
+    let mut file = create(local)?;
+    let stat = receive(driver, channel, stat, &mut file, progress)?;
+    file.flush()?;
+    set_mode(local, stat.mode())?;
+    Ok(stat)
+}
+
+/// Copies exactly `stat.size()` bytes from an `scp_recv` channel into `out`.
+fn receive(
+    driver: &Driver,
+    mut channel: Channel,
+    stat: ScpFileStat,
+    out: &mut dyn Write,
+    progress: &mut dyn FnMut(u64, u64),
+) -> Result<ScpFileStat, Error> {
+    let total = stat.size();
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut received = 0;
+    while received < total {
+        let want = buf.len().min((total - received) as usize);
+        let n = driver.read(&mut channel, &mut buf[..want])?;
+        if n == 0 {
+            break;
+        }
+        out.write_all(&buf[..n])?;
+        received += n as u64;
+        progress(received, total);
+    }
+    let _ = driver.close(&mut channel);
+    check_received(received, total)?;
+    Ok(stat)
+}
+
+/// The error for a local file that shrank while it was being sent; the server
+/// still expects the size announced at the start.
+pub(crate) fn truncated() -> Error {
+    Error::Io(io::ErrorKind::UnexpectedEof.into())
+}
+
+/// Fails if the channel ended before the announced size was received.
+pub(crate) fn check_received(received: u64, total: u64) -> Result<(), Error> {
+    if received != total {
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("received {} of {} bytes", received, total),
+        )));
+    }
+    Ok(())
+}
+
+/// Opens `path` for writing without exposing it to other users before the final mode is set.
+fn create(path: &Path) -> io::Result<File> {
+    create_options().open(path)
+}
+
+/// Options that create or truncate a download target, readable only by its owner.
+#[cfg(unix)]
+pub(crate) fn create_options() -> OpenOptions {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true).mode(0o600);
+    options
+}
+
+/// Options that create or truncate a download target.
+#[cfg(not(unix))]
+pub(crate) fn create_options() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    options
+}
+
+/// Applies the permission bits of a remote `st_mode`.
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: i32) -> io::Result<()> {
+    fs::set_permissions(path, permissions(mode))
+}
+
+/// The local permissions for a remote `st_mode`.
+#[cfg(unix)]
+pub(crate) fn permissions(mode: i32) -> fs::Permissions {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::Permissions::from_mode(mode as u32 & 0o7777)
+}
+
+/// Applies the write bits of a remote `st_mode`; other bits have no equivalent here.
+#[cfg(not(unix))]
+fn set_mode(path: &Path, mode: i32) -> io::Result<()> {
+    let mut perms = fs::metadata(path)?.permissions();
+    perms.set_readonly(mode & 0o222 == 0);
+    fs::set_permissions(path, perms)
+}
+
+/// Permission bits to create the remote copy with.
+#[cfg(unix)]
+pub(crate) fn mode(meta: &Metadata) -> i32 {
+    use std::os::unix::fs::PermissionsExt;
+
+    (meta.permissions().mode() & 0o7777) as i32
+}
+
+/// Permission bits to create the remote copy with.
+#[cfg(not(unix))]
+pub(crate) fn mode(meta: &Metadata) -> i32 {
+    if meta.permissions().readonly() {
+        SCPMODE & !0o222
+    } else {
+        SCPMODE
+    }
+}
+
+/// (mtime, atime) in seconds since the epoch, if the platform reports them.
+pub(crate) fn times(meta: &Metadata) -> Option<(u64, u64)> {
+    let secs = |t: std::io::Result<SystemTime>| t.ok()?.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs());
+    let mtime = secs(meta.modified())?;
+    Some((mtime, secs(meta.accessed()).unwrap_or(mtime)))
+}
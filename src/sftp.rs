@@ -0,0 +1,269 @@
+use crate::driver::Driver;
+use crate::error::Error;
+use crate::mux::ChannelSlot;
+use ssh2::{ErrorCode, OpenType};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::ManuallyDrop;
+use std::path::{Path, PathBuf};
+
+pub use ssh2::{FileStat, OpenFlags};
+
+/// libssh2 ends a directory listing with this code.
+const LIBSSH2_ERROR_FILE: i32 = -16;
+
+/// Mode given to files created by `create`.
+pub(crate) const FILEMODE: u32 = 0o644; // chmod 644
+
+/// An SFTP session on top of an `SSH` connection.
+///
+/// Every operation blocks until the server answers. The session stays usable
+/// for other work, such as port forwards, while the handle is alive.
+pub struct Sftp {
+    sftp: ManuallyDrop<ssh2::Sftp>,
+    driver: Driver,
+    _slot: ChannelSlot,
+}
+
+impl Sftp {
+    /// Starts the `sftp` subsystem on a new channel.
+    pub(crate) fn start(driver: &Driver, slot: ChannelSlot) -> Result<Self, Error> {
+        let sftp = driver.open(|| driver.sess.sftp()).map_err(Error::sftp)?;
+        Ok(Self {
+            sftp: ManuallyDrop::new(sftp),
+            driver: driver.clone(),
+            _slot: slot,
+        })
+    }
+
+    /// Runs an SFTP call to completion.
+    fn call<T, F>(&self, op: F) -> Result<T, Error>
+    where
+        F: FnMut() -> Result<T, ssh2::Error>,
+    {
+        self.driver.retry(op).map_err(Error::sftp)
+    }
+
+    /// Lists a directory, without `.` and `..`. Returned paths are joined onto `dir`.
+    pub fn readdir(&self, dir: &Path) -> Result<Vec<(PathBuf, FileStat)>, Error> {
+        let mut handle = self.call(|| self.sftp.opendir(dir))?;
+        let mut entries = Vec::new();
+        while list_entry(dir, self.driver.retry(|| handle.readdir()), &mut entries)? {}
+        self.call(|| handle.close())?;
+        Ok(entries)
+    }
+
+    /// Metadata for `path`, following symlinks.
+    pub fn stat(&self, path: &Path) -> Result<FileStat, Error> {
+        self.call(|| self.sftp.stat(path))
+    }
+
+    /// Metadata for `path` itself, even if it is a symlink.
+    pub fn lstat(&self, path: &Path) -> Result<FileStat, Error> {
+        self.call(|| self.sftp.lstat(path))
+    }
+
+    /// Creates a single directory with the permission bits `mode`.
+    pub fn mkdir(&self, path: &Path, mode: u32) -> Result<(), Error> {
+        self.call(|| self.sftp.mkdir(path, mode as i32))
+    }
+
+    /// Creates a directory and any missing parents, like `mkdir -p`.
+    pub fn mkdir_all(&self, path: &Path, mode: u32) -> Result<(), Error> {
+        let mut current = PathBuf::new();
+        for component in path.components() {
+            current.push(component);
+            if self.stat(&current).map(|s| s.is_dir()).unwrap_or(false) {
+                continue;
+            }
+            if let Err(e) = self.mkdir(&current, mode) {
+                // Someone else may have created it in the meantime.
+                if !self.stat(&current).map(|s| s.is_dir()).unwrap_or(false) {
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes a file or symlink.
+    pub fn remove_file(&self, path: &Path) -> Result<(), Error> {
+        self.call(|| self.sftp.unlink(path))
+    }
+
+    /// Removes an empty directory.
+    pub fn remove_dir(&self, path: &Path) -> Result<(), Error> {
+        self.call(|| self.sftp.rmdir(path))
+    }
+
+    /// Renames `src` to `dst`, replacing `dst` if the server allows it.
+    pub fn rename(&self, src: &Path, dst: &Path) -> Result<(), Error> {
+        self.call(|| self.sftp.rename(src, dst, None))
+    }
+
+    /// Creates a symlink at `link` pointing at `target`.
+    pub fn symlink(&self, target: &Path, link: &Path) -> Result<(), Error> {
+        self.call(|| self.sftp.symlink(target, link))
+    }
+
+    /// Where the symlink at `path` points.
+    pub fn readlink(&self, path: &Path) -> Result<PathBuf, Error> {
+        self.call(|| self.sftp.readlink(path))
+    }
+
+    /// The canonical absolute form of `path`.
+    pub fn realpath(&self, path: &Path) -> Result<PathBuf, Error> {
+        self.call(|| self.sftp.realpath(path))
+    }
+
+    /// Sets the permission bits of `path`.
+    pub fn chmod(&self, path: &Path, mode: u32) -> Result<(), Error> {
+        self.setstat(
+            path,
+            FileStat {
+                perm: Some(mode),
+                ..Self::unchanged()
+            },
+        )
+    }
+
+    /// Sets the owner and group of `path`.
+    pub fn chown(&self, path: &Path, uid: u32, gid: u32) -> Result<(), Error> {
+        self.setstat(
+            path,
+            FileStat {
+                uid: Some(uid),
+                gid: Some(gid),
+                ..Self::unchanged()
+            },
+        )
+    }
+
+    fn setstat(&self, path: &Path, stat: FileStat) -> Result<(), Error> {
+        self.call(|| self.sftp.setstat(path, stat.clone()))
+    }
+
+    /// A `FileStat` that leaves every attribute as it is.
+    fn unchanged() -> FileStat {
+        FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: None,
+            atime: None,
+            mtime: None,
+        }
+    }
+
+    /// Opens a file for reading.
+    pub fn open(&self, path: &Path) -> Result<SftpFile, Error> {
+        self.open_with(path, OpenFlags::READ, 0)
+    }
+
+    /// Opens a file for writing, creating it or truncating it.
+    pub fn create(&self, path: &Path) -> Result<SftpFile, Error> {
+        self.open_with(path, OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE, FILEMODE)
+    }
+
+    /// Opens a file with explicit flags, e.g. `WRITE | APPEND` to append or `WRITE` and a
+    /// seek to resume an interrupted transfer. `mode` applies if the file is created.
+    pub fn open_with(&self, path: &Path, flags: OpenFlags, mode: u32) -> Result<SftpFile, Error> {
+        let file = self.call(|| self.sftp.open_mode(path, flags, mode as i32, OpenType::File))?;
+        Ok(SftpFile {
+            file: ManuallyDrop::new(file),
+            driver: self.driver.clone(),
+        })
+    }
+}
+
+impl Drop for Sftp {
+    fn drop(&mut self) {
+        // Dropping `ssh2::Sftp` waits in blocking mode for the server to close the channel.
+        let sftp = &mut self.sftp;
+        self.driver.bounded(|| unsafe { ManuallyDrop::drop(sftp) });
+    }
+}
+
+/// Adds one `readdir` result to `entries`, skipping `.` and `..`.
+/// Returns false once the listing has ended.
+pub(crate) fn list_entry(
+    dir: &Path,
+    entry: Result<(PathBuf, FileStat), ssh2::Error>,
+    entries: &mut Vec<(PathBuf, FileStat)>,
+) -> Result<bool, Error> {
+    match entry {
+        Ok((name, stat)) => {
+            if name != Path::new(".") && name != Path::new("..") {
+                entries.push((dir.join(name), stat));
+            }
+            Ok(true)
+        }
+        Err(ref e) if e.code() == ErrorCode::Session(LIBSSH2_ERROR_FILE) => Ok(false),
+        Err(e) => Err(Error::sftp(e)),
+    }
+}
+
+/// An open remote file. Reads, writes and seeks block like a local `File`.
+pub struct SftpFile {
+    file: ManuallyDrop<ssh2::File>,
+    driver: Driver,
+}
+
+impl SftpFile {
+    /// Metadata for the open file.
+    pub fn stat(&mut self) -> Result<FileStat, Error> {
+        let file = &mut self.file;
+        self.driver.retry(|| file.stat()).map_err(Error::sftp)
+    }
+
+    /// Asks the server to flush the file to disk. Needs `fsync@openssh.com` support.
+    pub fn fsync(&mut self) -> Result<(), Error> {
+        let file = &mut self.file;
+        self.driver.retry(|| file.fsync()).map_err(Error::sftp)
+    }
+}
+
+impl Read for SftpFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let file = &mut self.file;
+        self.driver.retry_io(|| file.read(buf))
+    }
+}
+
+impl Write for SftpFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let file = &mut self.file;
+        self.driver.retry_io(|| file.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let file = &mut self.file;
+        self.driver.retry_io(|| file.flush())
+    }
+}
+
+impl Seek for SftpFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        // ssh2 hides a would-block while fetching the size for `End`, so resolve it here.
+        let pos = match pos {
+            SeekFrom::End(offset) => {
+                let size = self
+                    .stat()
+                    .map_err(io::Error::other)?
+                    .size
+                    .ok_or_else(|| io::Error::other("no file size available"))?;
+                SeekFrom::Start((size as i64 + offset) as u64)
+            }
+            pos => pos,
+        };
+        self.file.seek(pos)
+    }
+}
+
+impl Drop for SftpFile {
+    fn drop(&mut self) {
+        let file = &mut self.file;
+        let _ = self.driver.retry(|| file.close());
+        // The last open file may outlive its `Sftp` and end up shutting the subsystem down.
+        self.driver.bounded(|| unsafe { ManuallyDrop::drop(file) });
+    }
+}
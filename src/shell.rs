@@ -0,0 +1,200 @@
+use crate::driver::{Driver, WAIT_SLICE};
+use crate::error::Error;
+use crate::mux::ChannelSlot;
+use ssh2::Channel;
+use std::env;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Terminal type requested when `$TERM` is not set.
+const DEFAULT_TERM: &str = "xterm";
+
+/// Set by the SIGWINCH handler, cleared once the new size has been sent.
+static WINDOW_CHANGED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigwinch(_: libc::c_int) {
+    WINDOW_CHANGED.store(true, Ordering::Relaxed);
+}
+
+/// A remote login shell on a PTY, driven from the local terminal.
+pub struct InteractiveShell {
+    channel: Channel,
+    driver: Driver,
+    _slot: ChannelSlot,
+}
+
+impl InteractiveShell {
+    /// Requests a PTY sized like the local terminal and starts the user's shell.
+    pub(crate) fn open(driver: &Driver, slot: ChannelSlot) -> Result<Self, Error> {
+        let _opening = driver.opening()?;
+        let mut channel = driver.retry(|| driver.sess.channel_session()).map_err(Error::channel)?;
+        let term = env::var("TERM").unwrap_or_else(|_| DEFAULT_TERM.to_owned());
+        let dim = window_size().map(|(cols, rows)| (cols, rows, 0, 0));
+        driver.retry(|| channel.request_pty(&term, None, dim)).map_err(Error::channel)?;
+        driver.retry(|| channel.shell()).map_err(Error::channel)?;
+        Ok(Self {
+            channel,
+            driver: driver.clone(),
+            _slot: slot,
+        })
+    }
+
+    /// Hands the local terminal to the remote shell until it exits.
+    ///
+    /// The terminal is put into raw mode for the duration, stdin is forwarded
+    /// to the shell, its output is written to stdout/stderr and window size
+    /// changes are propagated. Returns the remote exit status.
+    pub fn run(mut self) -> Result<i32, Error> {
+        let _raw = RawMode::enable(libc::STDIN_FILENO)?;
+        let _winch = WinchHandler::install();
+        let (mut stdout, mut stderr) = (io::stdout(), io::stderr());
+        let mut buf = vec![0; 32 * 1024];
+        let mut stdin_open = true;
+
+        loop {
+            if WINDOW_CHANGED.swap(false, Ordering::Relaxed) {
+                if let Some((cols, rows)) = window_size() {
+                    let channel = &mut self.channel;
+                    self.driver
+                        .retry(|| channel.request_pty_size(cols, rows, None, None))
+                        .map_err(Error::channel)?;
+                }
+            }
+
+            let mut progress = false;
+            if let Some(n) = nonblocking(self.channel.read(&mut buf))? {
+                stdout.write_all(&buf[..n])?;
+                stdout.flush()?;
+                progress |= n > 0;
+            }
+            if let Some(n) = nonblocking(self.channel.stderr().read(&mut buf))? {
+                stderr.write_all(&buf[..n])?;
+                stderr.flush()?;
+                progress |= n > 0;
+            }
+            if !progress && self.channel.eof() {
+                break;
+            }
+
+            // Keep stdin responsive (e.g. for Ctrl-C) even while output is streaming.
+            let wait = if progress { Duration::from_millis(0) } else { WAIT_SLICE };
+            if stdin_open && poll_stdin(&self.driver, wait) {
+                let n = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr() as *mut _, buf.len()) };
+                if n > 0 {
+                    self.driver.write_all(&mut self.channel, &buf[..n as usize])?;
+                } else if n == 0 {
+                    stdin_open = false;
+                    let channel = &mut self.channel;
+                    self.driver.retry(|| channel.send_eof()).map_err(Error::channel)?;
+                } else if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                    return Err(io::Error::last_os_error().into());
+                }
+            } else if !stdin_open && !progress {
+                self.driver.wait(WAIT_SLICE);
+            }
+        }
+
+        self.driver.close_and_wait(&mut self.channel)?;
+        self.channel.exit_status().map_err(Error::channel)
+    }
+}
+
+impl Drop for InteractiveShell {
+    fn drop(&mut self) {
+        let _ = self.driver.close(&mut self.channel);
+    }
+}
+
+/// Waits up to `wait` for either stdin or the session socket to become readable.
+/// Returns true if stdin has data (or hit EOF).
+fn poll_stdin(driver: &Driver, wait: Duration) -> bool {
+    let mut fds = [
+        libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: driver.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+    let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, wait.as_millis() as libc::c_int) };
+    ready > 0 && fds[0].revents != 0
+}
+
+/// Maps `WouldBlock` to `None`.
+fn nonblocking(res: io::Result<usize>) -> io::Result<Option<usize>> {
+    match res {
+        Ok(n) => Ok(Some(n)),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Columns and rows of the local terminal, if stdout is one.
+fn window_size() -> Option<(u32, u32)> {
+    let mut size: libc::winsize = unsafe { mem::zeroed() };
+    let rc = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    if rc == 0 && size.ws_col > 0 {
+        Some((u32::from(size.ws_col), u32::from(size.ws_row)))
+    } else {
+        None
+    }
+}
+
+/// Puts a terminal into raw mode, restoring the previous settings on drop.
+/// Does nothing if the descriptor is not a terminal.
+struct RawMode {
+    fd: RawFd,
+    saved: Option<libc::termios>,
+}
+
+impl RawMode {
+    fn enable(fd: RawFd) -> io::Result<Self> {
+        if unsafe { libc::isatty(fd) } != 1 {
+            return Ok(Self { fd, saved: None });
+        }
+        let mut saved: libc::termios = unsafe { mem::zeroed() };
+        if unsafe { libc::tcgetattr(fd, &mut saved) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut raw = saved;
+        unsafe { libc::cfmakeraw(&mut raw) };
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { fd, saved: Some(saved) })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Some(saved) = self.saved {
+            unsafe { libc::tcsetattr(self.fd, libc::TCSANOW, &saved) };
+        }
+    }
+}
+
+/// Routes SIGWINCH to `WINDOW_CHANGED`, restoring the previous handler on drop.
+struct WinchHandler {
+    previous: libc::sighandler_t,
+}
+
+impl WinchHandler {
+    fn install() -> Self {
+        let handler = on_sigwinch as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        let previous = unsafe { libc::signal(libc::SIGWINCH, handler) };
+        Self { previous }
+    }
+}
+
+impl Drop for WinchHandler {
+    fn drop(&mut self) {
+        unsafe { libc::signal(libc::SIGWINCH, self.previous) };
+    }
+}
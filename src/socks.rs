@@ -0,0 +1,424 @@
+use crate::driver::{Driver, Link};
+use crate::error::Error;
+use crate::forward::{
+    follow, idle, pump_all, ready_to_open, start_pipe, ConnectionStats, PendingOpen, Session, Shared, Worker,
+};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+/// How long a client may take to send its SOCKS request.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SOCKS4: u8 = 4;
+const SOCKS5: u8 = 5;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+const SOCKS5_NO_AUTH: u8 = 0;
+const SOCKS5_NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const SOCKS5_GENERAL_FAILURE: u8 = 1;
+const SOCKS5_COMMAND_NOT_SUPPORTED: u8 = 7;
+const SOCKS5_ADDRESS_NOT_SUPPORTED: u8 = 8;
+const SOCKS4_GRANTED: u8 = 0x5a;
+const SOCKS4_REJECTED: u8 = 0x5b;
+
+/// A parsed CONNECT request.
+#[derive(Debug, PartialEq, Eq)]
+struct Request {
+    version: u8,
+    host: String,
+    port: u16,
+}
+
+/// Dynamic port forwarding, the equivalent of `ssh -D`.
+///
+/// Runs a SOCKS4/4a/5 server on a local port. Each CONNECT request is carried
+/// over its own `direct-tcpip` channel, so name resolution for 4a and SOCKS5
+/// domain requests happens on the server. Only unauthenticated CONNECT is
+/// supported. The proxy runs on a background thread until `stop` is called or
+/// the handle is dropped, and carries on after the `SSH` object reconnects.
+pub struct SocksProxy {
+    local_addr: SocketAddr,
+    worker: Worker,
+}
+
+impl SocksProxy {
+    pub(crate) fn start<A: ToSocketAddrs>(link: Arc<Link>, driver: Driver, bind_addr: A) -> Result<Self, Error> {
+        let listener = TcpListener::bind(bind_addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let worker = Worker::spawn(move |shared| run(&link, driver, &listener, shared));
+        Ok(Self { local_addr, worker })
+    }
+
+    /// The address the proxy is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Byte counters for every connection proxied so far.
+    pub fn connections(&self) -> Vec<ConnectionStats> {
+        self.worker.stats()
+    }
+
+    /// False once the proxy thread has exited.
+    pub fn is_running(&self) -> bool {
+        self.worker.is_running()
+    }
+
+    /// Stops the proxy, closing every open connection.
+    pub fn stop(mut self) -> Result<(), Error> {
+        self.worker.stop()
+    }
+}
+
+fn run(link: &Link, mut driver: Driver, listener: &TcpListener, shared: &Shared) -> Result<(), Error> {
+    // Handshakes only touch the client socket, so they run on their own threads
+    // and hand finished requests back here, where the session is driven.
+    let (tx, rx) = mpsc::channel();
+    let mut pipes = Vec::new();
+    let mut pending = None;
+    let mut buf = vec![0; 32 * 1024];
+    while !shared.stopped() {
+        // While the session is down, clients wait in the listen backlog. An open
+        // under way goes down with the old session, dropping its client.
+        match follow(link, &mut driver, &mut pipes) {
+            Session::Same => {}
+            Session::Switched => pending = None,
+            Session::Lost => {
+                pending = None;
+                continue;
+            }
+        }
+        let driver = &driver;
+        let mut progress = false;
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                progress = true;
+                // On macOS and the BSDs accepted sockets inherit O_NONBLOCK from the
+                // listener, which would defeat the handshake's read timeout.
+                if stream.set_nonblocking(false).is_ok() {
+                    let tx = tx.clone();
+                    thread::spawn(move || {
+                        if let Ok(req) = handshake(&stream) {
+                            let _ = tx.send((stream, peer, req));
+                        }
+                    });
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e.into()),
+        }
+        // One open at a time. At the channel limit, or while another open is under
+        // way on the session, finished handshakes wait for their turn.
+        if let Some(ready) = pending.is_none().then(|| ready_to_open(link, driver)).flatten() {
+            if let Ok((stream, peer, req)) = rx.try_recv() {
+                progress = true;
+                pending = Some(PendingOpen::new(driver, ready, &req.host, req.port, (stream, req.version), peer));
+            }
+        }
+        if let Some(opened) = pending.as_ref().and_then(|open| open.poll(driver)) {
+            progress = true;
+            let ((stream, version), peer, slot) = pending.take().expect("polled above").finish();
+            match opened {
+                Ok(mut channel) => {
+                    if reply(&stream, version, true).is_ok() {
+                        start_pipe(driver, &mut pipes, stream, channel, peer, shared, slot);
+                    } else {
+                        let _ = driver.close(&mut channel);
+                    }
+                }
+                Err(_) => {
+                    let _ = reply(&stream, version, false);
+                }
+            }
+        }
+        progress |= pump_all(driver, &mut pipes, &mut buf);
+        if !progress {
+            idle(driver);
+        }
+    }
+    for pipe in pipes {
+        pipe.close(&driver);
+    }
+    Ok(())
+}
+
+/// Reads a client's greeting and CONNECT request.
+fn handshake(mut stream: &TcpStream) -> io::Result<Request> {
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut version = [0; 1];
+    stream.read_exact(&mut version)?;
+    let req = match version[0] {
+        SOCKS4 => socks4_request(stream)?,
+        SOCKS5 => socks5_request(stream)?,
+        _ => return Err(invalid("unknown SOCKS version")),
+    };
+    stream.set_read_timeout(None)?;
+    Ok(req)
+}
+
+/// SOCKS4 and 4a: `CD DSTPORT DSTIP USERID\0 [HOST\0]`.
+fn socks4_request(mut stream: &TcpStream) -> io::Result<Request> {
+    let mut head = [0; 7];
+    stream.read_exact(&mut head)?;
+    let port = u16::from_be_bytes([head[1], head[2]]);
+    let ip = Ipv4Addr::new(head[3], head[4], head[5], head[6]);
+    read_nul_terminated(stream)?; // user id, ignored
+    if head[0] != CMD_CONNECT {
+        let _ = reply(stream, SOCKS4, false);
+        return Err(invalid("only CONNECT is supported"));
+    }
+    // 4a marks a trailing hostname with a destination of 0.0.0.x, x != 0.
+    let octets = ip.octets();
+    let host = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+        String::from_utf8(read_nul_terminated(stream)?).map_err(|_| invalid("hostname is not UTF-8"))?
+    } else {
+        ip.to_string()
+    };
+    Ok(Request {
+        version: SOCKS4,
+        host,
+        port,
+    })
+}
+
+/// SOCKS5: method negotiation, then `VER CMD RSV ATYP DST.ADDR DST.PORT`.
+fn socks5_request(mut stream: &TcpStream) -> io::Result<Request> {
+    let mut count = [0; 1];
+    stream.read_exact(&mut count)?;
+    let mut methods = vec![0; count[0] as usize];
+    stream.read_exact(&mut methods)?;
+    if !methods.contains(&SOCKS5_NO_AUTH) {
+        stream.write_all(&[SOCKS5, SOCKS5_NO_ACCEPTABLE_METHODS])?;
+        return Err(invalid("client requires authentication"));
+    }
+    stream.write_all(&[SOCKS5, SOCKS5_NO_AUTH])?;
+
+    let mut head = [0; 4];
+    stream.read_exact(&mut head)?;
+    if head[0] != SOCKS5 {
+        return Err(invalid("unexpected SOCKS version in request"));
+    }
+    let host = match head[3] {
+        ATYP_IPV4 => {
+            let mut ip = [0; 4];
+            stream.read_exact(&mut ip)?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0; 16];
+            stream.read_exact(&mut ip)?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        ATYP_DOMAIN => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len)?;
+            let mut name = vec![0; len[0] as usize];
+            stream.read_exact(&mut name)?;
+            String::from_utf8(name).map_err(|_| invalid("hostname is not UTF-8"))?
+        }
+        _ => {
+            socks5_reply(stream, SOCKS5_ADDRESS_NOT_SUPPORTED)?;
+            return Err(invalid("unsupported address type"));
+        }
+    };
+    let mut port = [0; 2];
+    stream.read_exact(&mut port)?;
+    if head[1] != CMD_CONNECT {
+        socks5_reply(stream, SOCKS5_COMMAND_NOT_SUPPORTED)?;
+        return Err(invalid("only CONNECT is supported"));
+    }
+    Ok(Request {
+        version: SOCKS5,
+        host,
+        port: u16::from_be_bytes(port),
+    })
+}
+
+/// Tells the client whether its CONNECT succeeded.
+fn reply(mut stream: &TcpStream, version: u8, ok: bool) -> io::Result<()> {
+    if version == SOCKS4 {
+        let status = if ok { SOCKS4_GRANTED } else { SOCKS4_REJECTED };
+        stream.write_all(&[0, status, 0, 0, 0, 0, 0, 0])
+    } else {
+        socks5_reply(stream, if ok { 0 } else { SOCKS5_GENERAL_FAILURE })
+    }
+}
+
+/// SOCKS5 reply with an unspecified bound address.
+fn socks5_reply(mut stream: &TcpStream, status: u8) -> io::Result<()> {
+    stream.write_all(&[SOCKS5, status, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+}
+
+fn read_nul_terminated(mut stream: &TcpStream) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut byte = [0; 1];
+    loop {
+        stream.read_exact(&mut byte)?;
+        match byte[0] {
+            0 => return Ok(out),
+            _ if out.len() >= 255 => return Err(invalid("field too long")),
+            b => out.push(b),
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forward::loopback_pair;
+    use std::net::Shutdown;
+
+    /// Sends `request` as a client would and runs the handshake on the other end.
+    /// Returns the outcome and every byte the proxy wrote back.
+    fn exchange(request: &[u8]) -> (io::Result<Request>, Vec<u8>) {
+        let (client, server) = loopback_pair().unwrap();
+        (&client).write_all(request).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let res = handshake(&server);
+        drop(server);
+        let mut replies = Vec::new();
+        // Closing with part of the request unread resets the connection; what was
+        // written before that still counts.
+        let _ = (&client).read_to_end(&mut replies);
+        (res, replies)
+    }
+
+    fn request(version: u8, host: &str, port: u16) -> Request {
+        Request {
+            version,
+            host: host.to_owned(),
+            port,
+        }
+    }
+
+    fn invalid_data(res: io::Result<Request>) -> String {
+        let err = res.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        err.to_string()
+    }
+
+    #[test]
+    fn socks4_connect() {
+        let (res, replies) = exchange(&[4, 1, 0x1f, 0x90, 10, 0, 0, 1, b'm', b'e', 0]);
+        assert_eq!(res.unwrap(), request(SOCKS4, "10.0.0.1", 8080));
+        assert!(replies.is_empty());
+    }
+
+    #[test]
+    fn socks4a_hostname() {
+        let mut bytes = vec![4, 1, 0, 80, 0, 0, 0, 1, 0];
+        bytes.extend_from_slice(b"example.com\0");
+        let (res, _) = exchange(&bytes);
+        assert_eq!(res.unwrap(), request(SOCKS4, "example.com", 80));
+    }
+
+    #[test]
+    fn socks4_zero_address_is_not_4a() {
+        let (res, _) = exchange(&[4, 1, 0, 80, 0, 0, 0, 0, 0]);
+        assert_eq!(res.unwrap(), request(SOCKS4, "0.0.0.0", 80));
+    }
+
+    #[test]
+    fn socks4_rejects_bind() {
+        let (res, replies) = exchange(&[4, 2, 0, 80, 10, 0, 0, 1, 0]);
+        assert_eq!(invalid_data(res), "only CONNECT is supported");
+        assert_eq!(replies, [0, SOCKS4_REJECTED, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn socks4_user_id_too_long() {
+        let mut bytes = vec![4, 1, 0, 80, 10, 0, 0, 1];
+        bytes.extend_from_slice(&[b'x'; 300]);
+        assert_eq!(invalid_data(exchange(&bytes).0), "field too long");
+    }
+
+    #[test]
+    fn socks5_ipv4() {
+        let (res, replies) = exchange(&[5, 2, 2, 0, 5, 1, 0, ATYP_IPV4, 127, 0, 0, 1, 0, 22]);
+        assert_eq!(res.unwrap(), request(SOCKS5, "127.0.0.1", 22));
+        assert_eq!(replies, [SOCKS5, SOCKS5_NO_AUTH]);
+    }
+
+    #[test]
+    fn socks5_domain() {
+        let mut bytes = vec![5, 1, 0, 5, 1, 0, ATYP_DOMAIN, 11];
+        bytes.extend_from_slice(b"example.com");
+        bytes.extend_from_slice(&[1, 187]);
+        let (res, _) = exchange(&bytes);
+        assert_eq!(res.unwrap(), request(SOCKS5, "example.com", 443));
+    }
+
+    #[test]
+    fn socks5_ipv6() {
+        let mut bytes = vec![5, 1, 0, 5, 1, 0, ATYP_IPV6];
+        bytes.extend_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).octets());
+        bytes.extend_from_slice(&[0, 80]);
+        let (res, _) = exchange(&bytes);
+        assert_eq!(res.unwrap(), request(SOCKS5, "2001:db8::1", 80));
+    }
+
+    #[test]
+    fn socks5_requires_no_auth_method() {
+        let (res, replies) = exchange(&[5, 1, 2]);
+        assert_eq!(invalid_data(res), "client requires authentication");
+        assert_eq!(replies, [SOCKS5, SOCKS5_NO_ACCEPTABLE_METHODS]);
+    }
+
+    #[test]
+    fn socks5_rejects_other_commands() {
+        let (res, replies) = exchange(&[5, 1, 0, 5, 3, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(invalid_data(res), "only CONNECT is supported");
+        assert_eq!(replies[..2], [SOCKS5, SOCKS5_NO_AUTH]);
+        assert_eq!(replies[2..], [SOCKS5, SOCKS5_COMMAND_NOT_SUPPORTED, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn socks5_rejects_unknown_address_type() {
+        let (res, replies) = exchange(&[5, 1, 0, 5, 1, 0, 9]);
+        assert_eq!(invalid_data(res), "unsupported address type");
+        assert_eq!(replies[2..4], [SOCKS5, SOCKS5_ADDRESS_NOT_SUPPORTED]);
+    }
+
+    #[test]
+    fn unknown_version() {
+        assert_eq!(invalid_data(exchange(&[6, 1]).0), "unknown SOCKS version");
+    }
+
+    #[test]
+    fn truncated_request() {
+        let err = exchange(&[5, 1, 0, 5, 1]).0.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn replies() {
+        let (client, server) = loopback_pair().unwrap();
+        reply(&server, SOCKS4, true).unwrap();
+        reply(&server, SOCKS4, false).unwrap();
+        reply(&server, SOCKS5, true).unwrap();
+        reply(&server, SOCKS5, false).unwrap();
+        drop(server);
+        let mut bytes = Vec::new();
+        (&client).read_to_end(&mut bytes).unwrap();
+        assert_eq!(
+            bytes,
+            [
+                &[0, SOCKS4_GRANTED, 0, 0, 0, 0, 0, 0][..],
+                &[0, SOCKS4_REJECTED, 0, 0, 0, 0, 0, 0],
+                &[SOCKS5, 0, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0],
+                &[SOCKS5, SOCKS5_GENERAL_FAILURE, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0],
+            ]
+            .concat()
+        );
+    }
+}
@@ -0,0 +1,283 @@
+use crate::error::Error;
+use crate::options::{AddressFamily, ConnectOptions};
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long an attempt gets before the next address is tried alongside it,
+/// as recommended for Happy Eyeballs (RFC 8305).
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connects to `host:port`, trying every address it resolves to.
+///
+/// Addresses are tried in the order set by the address family preference,
+/// alternating between IPv4 and IPv6. A new attempt starts whenever the previous
+/// one fails or has been pending for `ATTEMPT_DELAY`, and the first connection
+/// made wins. `host` may be a name or an IPv4 or IPv6 literal.
+pub(crate) fn connect(host: &str, port: u16, options: &ConnectOptions) -> Result<TcpStream, Error> {
+    let addrs = order((host, port).to_socket_addrs().map_err(Error::Connect)?.collect(), options.address_family);
+    if addrs.is_empty() {
+        let msg = format!("{} has no usable address", host);
+        return Err(Error::Connect(io::Error::new(io::ErrorKind::NotFound, msg)));
+    }
+    let deadline = options.connect_timeout.map(|t| Instant::now() + t);
+    let (tx, rx) = mpsc::channel();
+    let mut queue = addrs.into_iter();
+    let mut pending = Vec::new();
+    let mut failures = Vec::new();
+    let mut start_next = true;
+    loop {
+        let remaining = match deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => Some(remaining),
+                _ => break,
+            },
+            None => None,
+        };
+        if start_next {
+            if let Some(addr) = queue.next() {
+                attempt(addr, remaining, tx.clone());
+                pending.push(addr);
+            }
+        }
+        if pending.is_empty() {
+            break;
+        }
+        // Give the attempts in flight a head start before adding another.
+        let wait = match (queue.len(), remaining) {
+            (0, Some(remaining)) => remaining,
+            (0, None) => Duration::MAX,
+            (_, remaining) => remaining.map_or(ATTEMPT_DELAY, |r| r.min(ATTEMPT_DELAY)),
+        };
+        start_next = match rx.recv_timeout(wait) {
+            Ok((_, Ok(stream))) => return Ok(stream),
+            Ok((addr, Err(e))) => {
+                pending.retain(|a| *a != addr);
+                failures.push((addr, e));
+                true
+            }
+            Err(RecvTimeoutError::Timeout) => true,
+            Err(RecvTimeoutError::Disconnected) => unreachable!("a sender is kept alive"),
+        };
+    }
+    // Out of time: whatever was not answered counts as timed out.
+    for addr in pending.into_iter().chain(queue) {
+        failures.push((addr, io::ErrorKind::TimedOut.into()));
+    }
+    Err(failure(host, failures))
+}
+
+/// `connect` for async callers: attempts run as tasks on the current Tokio runtime.
+/// The stream is returned in non-blocking mode.
+#[cfg(all(feature = "async", unix))]
+pub(crate) async fn connect_async(host: &str, port: u16, options: &ConnectOptions) -> Result<TcpStream, Error> {
+    use tokio::task::JoinSet;
+    use tokio::time;
+
+    let resolved = tokio::net::lookup_host((host, port)).await.map_err(Error::Connect)?;
+    let addrs = order(resolved.collect(), options.address_family);
+    if addrs.is_empty() {
+        let msg = format!("{} has no usable address", host);
+        return Err(Error::Connect(io::Error::new(io::ErrorKind::NotFound, msg)));
+    }
+    let deadline = options.connect_timeout.map(|t| time::Instant::now() + t);
+    let mut attempts = JoinSet::new();
+    let mut queue = addrs.into_iter();
+    let mut pending = Vec::new();
+    let mut failures = Vec::new();
+    loop {
+        if deadline.is_some_and(|deadline| time::Instant::now() >= deadline) {
+            break;
+        }
+        if let Some(addr) = queue.next() {
+            attempts.spawn(async move { (addr, tokio::net::TcpStream::connect(addr).await) });
+            pending.push(addr);
+        }
+        if attempts.is_empty() {
+            break;
+        }
+        // Give the attempts in flight a head start before adding another.
+        let wait = match queue.len() {
+            0 => deadline,
+            _ => {
+                let next = time::Instant::now() + ATTEMPT_DELAY;
+                Some(deadline.map_or(next, |deadline| deadline.min(next)))
+            }
+        };
+        let finished = match wait {
+            Some(wait) => match time::timeout_at(wait, attempts.join_next()).await {
+                Ok(finished) => finished,
+                Err(_) => continue,
+            },
+            None => attempts.join_next().await,
+        };
+        match finished {
+            Some(Ok((_, Ok(stream)))) => return stream.into_std().map_err(Error::Connect),
+            Some(Ok((addr, Err(e)))) => {
+                pending.retain(|a| *a != addr);
+                failures.push((addr, e));
+            }
+            // Attempts are never aborted while the set is alive, and do not panic.
+            Some(Err(_)) | None => {}
+        }
+    }
+    // Out of time: whatever was not answered counts as timed out.
+    for addr in pending.into_iter().chain(queue) {
+        failures.push((addr, io::ErrorKind::TimedOut.into()));
+    }
+    Err(failure(host, failures))
+}
+
+/// Starts connecting to `addr` on its own thread, reporting the outcome on `tx`.
+/// Attempts that lose the race finish on their own and their streams are dropped.
+fn attempt(addr: SocketAddr, timeout: Option<Duration>, tx: mpsc::Sender<(SocketAddr, io::Result<TcpStream>)>) {
+    thread::spawn(move || {
+        let res = match timeout {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+            None => TcpStream::connect(addr),
+        };
+        let _ = tx.send((addr, res));
+    });
+}
+
+/// Strips the brackets from an IPv6 literal written as `[addr]`.
+pub(crate) fn unbracket(host: &str) -> &str {
+    host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host)
+}
+
+/// Puts addresses in the order they are tried, dropping excluded families.
+fn order(addrs: Vec<SocketAddr>, family: AddressFamily) -> Vec<SocketAddr> {
+    let (v4, v6): (Vec<_>, Vec<_>) = addrs.iter().partition(|a| a.is_ipv4());
+    let v4_first = match family {
+        AddressFamily::Ipv4Only => return v4,
+        AddressFamily::Ipv6Only => return v6,
+        AddressFamily::PreferIpv4 => true,
+        AddressFamily::PreferIpv6 => false,
+        AddressFamily::Any => addrs.first().is_none_or(|a| a.is_ipv4()),
+    };
+    let (first, second) = if v4_first { (v4, v6) } else { (v6, v4) };
+    let mut ordered = Vec::with_capacity(addrs.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// The error for a host none of whose addresses could be reached.
+fn failure(host: &str, mut failures: Vec<(SocketAddr, io::Error)>) -> Error {
+    if failures.iter().all(|(_, e)| e.kind() == io::ErrorKind::TimedOut) {
+        return Error::Timeout;
+    }
+    if failures.len() == 1 {
+        return Error::Connect(failures.remove(0).1);
+    }
+    Error::ConnectFailed {
+        host: host.to_owned(),
+        attempts: failures,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    fn mixed() -> Vec<SocketAddr> {
+        addrs(&["[2001:db8::1]:22", "192.0.2.1:22", "192.0.2.2:22", "192.0.2.3:22", "[2001:db8::2]:22"])
+    }
+
+    #[test]
+    fn any_follows_the_resolver_and_alternates() {
+        assert_eq!(
+            order(mixed(), AddressFamily::Any),
+            addrs(&["[2001:db8::1]:22", "192.0.2.1:22", "[2001:db8::2]:22", "192.0.2.2:22", "192.0.2.3:22"])
+        );
+        let v4_first = addrs(&["192.0.2.1:22", "[2001:db8::1]:22", "[2001:db8::2]:22"]);
+        assert_eq!(
+            order(v4_first, AddressFamily::Any),
+            addrs(&["192.0.2.1:22", "[2001:db8::1]:22", "[2001:db8::2]:22"])
+        );
+    }
+
+    #[test]
+    fn preferences_choose_the_first_family() {
+        assert_eq!(
+            order(mixed(), AddressFamily::PreferIpv4),
+            addrs(&["192.0.2.1:22", "[2001:db8::1]:22", "192.0.2.2:22", "[2001:db8::2]:22", "192.0.2.3:22"])
+        );
+        assert_eq!(
+            order(mixed(), AddressFamily::PreferIpv6),
+            addrs(&["[2001:db8::1]:22", "192.0.2.1:22", "[2001:db8::2]:22", "192.0.2.2:22", "192.0.2.3:22"])
+        );
+    }
+
+    #[test]
+    fn only_drops_the_other_family() {
+        assert_eq!(
+            order(mixed(), AddressFamily::Ipv4Only),
+            addrs(&["192.0.2.1:22", "192.0.2.2:22", "192.0.2.3:22"])
+        );
+        assert_eq!(order(mixed(), AddressFamily::Ipv6Only), addrs(&["[2001:db8::1]:22", "[2001:db8::2]:22"]));
+        assert!(order(addrs(&["192.0.2.1:22"]), AddressFamily::Ipv6Only).is_empty());
+        assert!(order(Vec::new(), AddressFamily::Any).is_empty());
+    }
+
+    #[test]
+    fn unbracket_strips_only_matching_brackets() {
+        assert_eq!(unbracket("[2001:db8::10]"), "2001:db8::10");
+        assert_eq!(unbracket("[::1]"), "::1");
+        assert_eq!(unbracket("2001:db8::10"), "2001:db8::10");
+        assert_eq!(unbracket("example.com"), "example.com");
+        assert_eq!(unbracket("[::1"), "[::1");
+        assert_eq!(unbracket("::1]"), "::1]");
+    }
+
+    fn err(kind: io::ErrorKind) -> io::Error {
+        io::Error::from(kind)
+    }
+
+    #[test]
+    fn all_timeouts_is_a_timeout() {
+        let failures = vec![
+            (mixed()[0], err(io::ErrorKind::TimedOut)),
+            (mixed()[1], err(io::ErrorKind::TimedOut)),
+        ];
+        assert!(matches!(failure("host", failures), Error::Timeout));
+    }
+
+    #[test]
+    fn single_failure_is_reported_as_is() {
+        let failures = vec![(mixed()[1], err(io::ErrorKind::ConnectionRefused))];
+        match failure("host", failures) {
+            Error::Connect(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused),
+            other => panic!("expected Connect, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mixed_failures_list_every_attempt() {
+        let failures = vec![
+            (mixed()[0], err(io::ErrorKind::ConnectionRefused)),
+            (mixed()[1], err(io::ErrorKind::TimedOut)),
+        ];
+        match failure("host", failures) {
+            Error::ConnectFailed { host, attempts } => {
+                assert_eq!(host, "host");
+                let tried: Vec<_> = attempts.iter().map(|(addr, e)| (*addr, e.kind())).collect();
+                assert_eq!(
+                    tried,
+                    vec![(mixed()[0], io::ErrorKind::ConnectionRefused), (mixed()[1], io::ErrorKind::TimedOut)]
+                );
+            }
+            other => panic!("expected ConnectFailed, got {:?}", other),
+        }
+    }
+}